@@ -0,0 +1,295 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tar::{EntryType, Header};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    sink::{verify_size, OutputSink},
+    Error,
+};
+
+const TAR_BLOCK_SIZE: u64 = 512;
+const TAR_NAME_SIZE: usize = 100;
+
+/// Writer passing data through while counting the written bytes
+struct CountingWriter<'a, W: Write> {
+    inner: &'a mut W,
+    count: &'a mut u64,
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        *self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+enum TarOutput {
+    Plain(BufWriter<File>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Write for TarOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TarOutput::Plain(w) => w.write(buf),
+            TarOutput::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TarOutput::Plain(w) => w.flush(),
+            TarOutput::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+/// Streams the extracted content into a tar archive, optionally zstd compressed
+pub struct TarSink {
+    path: PathBuf,
+    compress: bool,
+    mtime: u64,
+    out: Option<TarOutput>,
+    dirs: HashSet<String>,
+    written: u64,
+}
+
+impl TarSink {
+    pub fn new(path: &str, compress: bool) -> Self {
+        TarSink {
+            path: PathBuf::from(path),
+            compress,
+            mtime: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            out: None,
+            dirs: HashSet::new(),
+            written: 0,
+        }
+    }
+
+    /// Writes the header of an entry, preceded by a GNU long name entry if the
+    /// path does not fit into the header
+    fn write_header(&mut self, path: &str, entry_type: EntryType, size: u64) -> io::Result<()> {
+        let mtime = self.mtime;
+        let out = self.out.as_mut().ok_or(io::ErrorKind::NotConnected)?;
+
+        let name = path.as_bytes();
+        if name.len() > TAR_NAME_SIZE {
+            let mut header = Header::new_gnu();
+            header.as_old_mut().name[..13].copy_from_slice(b"././@LongLink");
+            header.set_mode(0o644);
+            header.set_size(name.len() as u64 + 1);
+            header.set_entry_type(EntryType::GNULongName);
+            header.set_cksum();
+            out.write_all(header.as_bytes())?;
+            out.write_all(name)?;
+            out.write_all(&[0])?;
+            write_tar_padding(out, name.len() as u64 + 1)?;
+        }
+
+        let mut header = Header::new_gnu();
+        let name_len = name.len().min(TAR_NAME_SIZE);
+        header.as_old_mut().name[..name_len].copy_from_slice(&name[..name_len]);
+        header.set_mode(if entry_type == EntryType::Directory {
+            0o755
+        } else {
+            0o644
+        });
+        header.set_size(size);
+        header.set_mtime(mtime);
+        header.set_entry_type(entry_type);
+        header.set_cksum();
+        out.write_all(header.as_bytes())
+    }
+}
+
+/// Pads entry data of the given size to the next tar block
+fn write_tar_padding<W: Write>(out: &mut W, size: u64) -> io::Result<()> {
+    let rest = size % TAR_BLOCK_SIZE;
+    if rest > 0 {
+        let padding = (TAR_BLOCK_SIZE - rest) as usize;
+        out.write_all(&[0; TAR_BLOCK_SIZE as usize][..padding])?;
+    }
+    Ok(())
+}
+
+impl OutputSink for TarSink {
+    fn prepare(&mut self) -> Result<(), Error> {
+        let file = File::create(&self.path)
+            .map_err(|e| Error::io(format!("Error creating archive {:?}", &self.path), e))?;
+        let writer = BufWriter::new(file);
+        self.out = Some(if self.compress {
+            let encoder = zstd::Encoder::new(writer, 0)
+                .map_err(|e| Error::io(format!("Error creating archive {:?}", &self.path), e))?;
+            TarOutput::Zstd(encoder)
+        } else {
+            TarOutput::Plain(writer)
+        });
+        Ok(())
+    }
+
+    fn exists(&mut self, path: &str) -> Result<bool, Error> {
+        Ok(self.dirs.contains(path))
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), Error> {
+        self.write_header(&format!("{}/", path), EntryType::Directory, 0)
+            .map_err(|e| Error::io(format!("Error writing to archive {:?}", &self.path), e))?;
+        self.dirs.insert(path.to_string());
+        Ok(())
+    }
+
+    fn file_size(&mut self, _path: &str) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    fn create_file(&mut self, path: &str, size: u64) -> Result<Box<dyn Write + '_>, Error> {
+        self.write_header(path, EntryType::Regular, size)
+            .map_err(|e| Error::io(format!("Error writing to archive {:?}", &self.path), e))?;
+        self.written = 0;
+        let out = self.out.as_mut().ok_or(Error::Invalid(format!(
+            "Archive {:?} is not open",
+            &self.path
+        )))?;
+        Ok(Box::new(CountingWriter {
+            inner: out,
+            count: &mut self.written,
+        }))
+    }
+
+    fn finalize(&mut self, path: &str, size: u64) -> Result<(), Error> {
+        let out = self.out.as_mut().ok_or(Error::Invalid(format!(
+            "Archive {:?} is not open",
+            &self.path
+        )))?;
+        write_tar_padding(out, self.written)
+            .map_err(|e| Error::io(format!("Error writing to archive {:?}", &self.path), e))?;
+        verify_size(&self.display_path(path), self.written, size)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        let mut out = self.out.take().ok_or(Error::Invalid(format!(
+            "Archive {:?} is not open",
+            &self.path
+        )))?;
+        // end of archive marker: two empty blocks
+        out.write_all(&[0; 2 * TAR_BLOCK_SIZE as usize])
+            .map_err(|e| Error::io(format!("Error writing to archive {:?}", &self.path), e))?;
+        let mut writer = match out {
+            TarOutput::Plain(w) => w,
+            TarOutput::Zstd(encoder) => encoder
+                .finish()
+                .map_err(|e| Error::io(format!("Error writing to archive {:?}", &self.path), e))?,
+        };
+        writer
+            .flush()
+            .map_err(|e| Error::io(format!("Error writing to archive {:?}", &self.path), e))
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        format!("{}:{}", self.path.to_string_lossy(), path)
+    }
+}
+
+/// Streams the extracted content into a zip archive
+pub struct ZipSink {
+    path: PathBuf,
+    zip: Option<ZipWriter<File>>,
+    dirs: HashSet<String>,
+    written: u64,
+}
+
+impl ZipSink {
+    pub fn new(path: &str) -> Self {
+        ZipSink {
+            path: PathBuf::from(path),
+            zip: None,
+            dirs: HashSet::new(),
+            written: 0,
+        }
+    }
+}
+
+impl OutputSink for ZipSink {
+    fn prepare(&mut self) -> Result<(), Error> {
+        let file = File::create(&self.path)
+            .map_err(|e| Error::io(format!("Error creating archive {:?}", &self.path), e))?;
+        self.zip = Some(ZipWriter::new(file));
+        Ok(())
+    }
+
+    fn exists(&mut self, path: &str) -> Result<bool, Error> {
+        Ok(self.dirs.contains(path))
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), Error> {
+        let options = SimpleFileOptions::default().unix_permissions(0o755);
+        let zip = self.zip.as_mut().ok_or(Error::Invalid(format!(
+            "Archive {:?} is not open",
+            &self.path
+        )))?;
+        zip.add_directory(path, options).map_err(|e| Error::Zip {
+            context: format!("Error writing to archive {:?}", &self.path),
+            source: e,
+        })?;
+        self.dirs.insert(path.to_string());
+        Ok(())
+    }
+
+    fn file_size(&mut self, _path: &str) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    fn create_file(&mut self, path: &str, size: u64) -> Result<Box<dyn Write + '_>, Error> {
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .unix_permissions(0o644)
+            .large_file(size >= u32::MAX as u64);
+        let zip = self.zip.as_mut().ok_or(Error::Invalid(format!(
+            "Archive {:?} is not open",
+            &self.path
+        )))?;
+        zip.start_file(path, options).map_err(|e| Error::Zip {
+            context: format!("Error writing to archive {:?}", &self.path),
+            source: e,
+        })?;
+        self.written = 0;
+        Ok(Box::new(CountingWriter {
+            inner: zip,
+            count: &mut self.written,
+        }))
+    }
+
+    fn finalize(&mut self, path: &str, size: u64) -> Result<(), Error> {
+        verify_size(&self.display_path(path), self.written, size)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        let zip = self.zip.take().ok_or(Error::Invalid(format!(
+            "Archive {:?} is not open",
+            &self.path
+        )))?;
+        zip.finish().map_err(|e| Error::Zip {
+            context: format!("Error writing to archive {:?}", &self.path),
+            source: e,
+        })?;
+        Ok(())
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        format!("{}:{}", self.path.to_string_lossy(), path)
+    }
+}
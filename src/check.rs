@@ -0,0 +1,626 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{Read, Seek, SeekFrom},
+};
+
+use crate::{
+    entry::{
+        DirEntry, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_HIDDEN, ATTR_NORMAL, ATTR_READ_ONLY,
+        ATTR_SYSTEM, MAX_NAME_LEN,
+    },
+    meta::SECTOR_SIZE,
+    Error, XIso, IMAGE_BUFFER_SIZE,
+};
+
+/// Sectors of the game partition in front of the first directory table, up to and
+/// including the volume descriptor
+const RESERVED_SECTORS: u32 = 33;
+/// Attribute flags used by XDVDFS, see [`DirEntry::attribute_names`]
+const KNOWN_ATTRIBUTES: u8 =
+    ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_DIRECTORY | ATTR_ARCHIVE | ATTR_NORMAL;
+
+/// Severity of a [`Finding`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Unusual, but the image works as expected
+    Info,
+    /// Deviation from what the original tools write, e.g. unbalanced trees
+    Warning,
+    /// Structural damage, files can not be found or read correctly
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Problem found by [`XIso::check`]
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Path of the affected file or directory inside the image
+    pub path: String,
+    pub message: String,
+}
+
+/// Findings of a structural check of the image
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub files: u32,
+    pub directories: u32,
+    pub findings: Vec<Finding>,
+}
+
+impl CheckReport {
+    /// Number of findings with the given severity
+    pub fn count(&self, severity: Severity) -> usize {
+        self.findings
+            .iter()
+            .filter(|f| f.severity == severity)
+            .count()
+    }
+
+    /// `true` if there are no errors, warnings are accepted
+    pub fn is_ok(&self) -> bool {
+        self.count(Severity::Error) == 0
+    }
+
+    fn add(&mut self, severity: Severity, path: &str, message: String) {
+        self.findings.push(Finding {
+            severity,
+            path: path.to_string(),
+            message,
+        });
+    }
+}
+
+/// Sectors used by a file or a directory table
+struct Extent {
+    sector: u32,
+    size: u32,
+    path: String,
+}
+
+impl Extent {
+    /// Sector behind the extent, which can be beyond the sectors XDVDFS can address
+    fn end_sector(&self) -> u64 {
+        self.sector as u64 + self.size.div_ceil(SECTOR_SIZE) as u64
+    }
+}
+
+impl<R: Read + Seek> XIso<R> {
+    /// Validates the structure of the image: locations of files and directory
+    /// tables, order and balance of the directory trees, names and attributes, and
+    /// the padding between files. Open damaged images with
+    /// [`XIso::from_path_salvage`] to get their broken tables as findings.
+    pub fn check(&mut self) -> Result<CheckReport, Error> {
+        let image_size = self
+            .reader
+            .seek(SeekFrom::End(0))
+            .map_err(|e| Error::image("Error reading from ISO file", e))?;
+
+        let mut report = CheckReport::default();
+        // tables skipped when opening the image for salvaging
+        for damage in self.damage.iter() {
+            report.add(Severity::Error, &damage.path, damage.reason.clone());
+        }
+        let mut extents = vec![Extent {
+            sector: 0,
+            size: RESERVED_SECTORS * SECTOR_SIZE,
+            path: "(volume descriptor)".to_string(),
+        }];
+
+        let root = std::mem::take(&mut self.root);
+        let (sector, size) = (self.meta.root_dir_sector, self.meta.root_dir_size);
+        extents.push(Extent {
+            sector,
+            size,
+            path: "/".to_string(),
+        });
+        if !self.is_damaged("/") {
+            check_tree(&root, "/", &mut report);
+        }
+        self.check_dir(&root, "", &mut extents, &mut report)?;
+        self.root = root;
+
+        self.check_extents(&mut extents, image_size, &mut report)?;
+
+        for finding in report.findings.iter() {
+            println!(
+                "{}: {}: {}",
+                finding.severity, finding.path, finding.message
+            );
+        }
+        println!(
+            "Checked {} files in {} directories: {} errors, {} warnings",
+            report.files,
+            report.directories,
+            report.count(Severity::Error),
+            report.count(Severity::Warning)
+        );
+        Ok(report)
+    }
+
+    fn check_dir(
+        &mut self,
+        entries: &[DirEntry],
+        parent: &str,
+        extents: &mut Vec<Extent>,
+        report: &mut CheckReport,
+    ) -> Result<(), Error> {
+        let mut names = HashSet::new();
+        for entry in entries.iter() {
+            let path = format!("{}/{}", parent, entry.name);
+            check_name(entry, &path, report);
+            if !names.insert(entry.name.to_ascii_uppercase()) {
+                report.add(
+                    Severity::Error,
+                    &path,
+                    "name appears more than once in the directory".to_string(),
+                );
+            }
+            if entry.attributes & !KNOWN_ATTRIBUTES != 0 {
+                report.add(
+                    Severity::Warning,
+                    &path,
+                    format!("unknown attribute flags {:#04x}", entry.attributes),
+                );
+            }
+
+            if entry.size > 0 {
+                extents.push(Extent {
+                    sector: entry.sector,
+                    size: entry.size,
+                    path: path.clone(),
+                });
+            }
+            if entry.is_dir() {
+                report.directories += 1;
+                let subdir = entry.subdir.as_deref().unwrap_or_default();
+                if !self.is_damaged(&path) {
+                    check_tree(subdir, &path, report);
+                }
+                self.check_dir(subdir, &path, extents, report)?;
+            } else {
+                report.files += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the directory table of the path was found broken when opening the
+    /// image, its entries are incomplete then
+    fn is_damaged(&self, path: &str) -> bool {
+        self.damage.iter().any(|damage| damage.path == path)
+    }
+
+    /// Checks that all extents lie within the image and do not overlap, and that
+    /// the sectors between them are zero
+    fn check_extents(
+        &mut self,
+        extents: &mut [Extent],
+        image_size: u64,
+        report: &mut CheckReport,
+    ) -> Result<(), Error> {
+        for extent in extents.iter() {
+            let end = self.meta.root_offset
+                + extent.sector as u64 * SECTOR_SIZE as u64
+                + extent.size as u64;
+            if end > image_size {
+                report.add(
+                    Severity::Error,
+                    &extent.path,
+                    format!(
+                        "sectors {}-{} extend {} bytes beyond the end of the image",
+                        extent.sector,
+                        extent.end_sector() - 1,
+                        end - image_size
+                    ),
+                );
+            }
+        }
+
+        extents.sort_by_key(|extent| extent.sector);
+        let mut furthest: Option<&Extent> = None;
+        for extent in extents.iter() {
+            match furthest {
+                Some(last) if (extent.sector as u64) < last.end_sector() => {
+                    report.add(
+                        Severity::Error,
+                        &extent.path,
+                        format!(
+                            "sectors {}-{} overlap with {} (sectors {}-{})",
+                            extent.sector,
+                            extent.end_sector() - 1,
+                            last.path,
+                            last.sector,
+                            last.end_sector() - 1
+                        ),
+                    );
+                }
+                Some(last) if extent.sector as u64 > last.end_sector() => {
+                    let dirty =
+                        self.count_dirty_sectors(last.end_sector(), extent.sector as u64)?;
+                    if dirty > 0 {
+                        report.add(
+                            Severity::Warning,
+                            &extent.path,
+                            format!(
+                                "{} of the sectors {}-{} in front of it are not zero",
+                                dirty,
+                                last.end_sector(),
+                                extent.sector - 1
+                            ),
+                        );
+                    }
+                }
+                _ => {}
+            }
+            if furthest.is_none_or(|last| extent.end_sector() > last.end_sector()) {
+                furthest = Some(extent);
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of sectors in the range which contain anything but zeros. Sectors
+    /// beyond the end of the image are not counted.
+    fn count_dirty_sectors(&mut self, first: u64, end: u64) -> Result<u32, Error> {
+        let position = self.meta.root_offset + first * SECTOR_SIZE as u64;
+        self.reader
+            .seek(SeekFrom::Start(position))
+            .map_err(|e| Error::image("Error reading from ISO file", e))?;
+
+        let mut dirty = 0;
+        let mut buffer = vec![0; IMAGE_BUFFER_SIZE];
+        let mut remaining = (end - first) * SECTOR_SIZE as u64;
+        while remaining > 0 {
+            let chunk_size = remaining.min(buffer.len() as u64) as usize;
+            let count = self
+                .reader
+                .read(&mut buffer[..chunk_size])
+                .map_err(|e| Error::image("Error reading from ISO file", e))?;
+            if count == 0 {
+                break;
+            }
+            dirty += buffer[..count]
+                .chunks(SECTOR_SIZE as usize)
+                .filter(|sector| sector.iter().any(|&byte| byte != 0))
+                .count() as u32;
+            remaining -= count as u64;
+        }
+        Ok(dirty)
+    }
+}
+
+/// Checks that an in-order walk of the binary tree of a directory table gives the
+/// names in ascending order and that the tree is balanced like an AVL tree. Works
+/// on the parsed entries with their table offsets and links, so entries found by
+/// scanning the table but not linked into the tree are reported as well.
+fn check_tree(entries: &[DirEntry], path: &str, report: &mut CheckReport) {
+    if entries.is_empty() {
+        return;
+    }
+    // entries by their offset in units of 4 bytes like the links
+    let nodes: HashMap<u32, &DirEntry> = entries
+        .iter()
+        .map(|entry| (entry.table_offset / 4, entry))
+        .collect();
+
+    let mut preorder = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![0_u32];
+    while let Some(offset) = stack.pop() {
+        let Some(entry) = nodes.get(&offset) else {
+            report.add(
+                Severity::Error,
+                path,
+                format!(
+                    "directory tree links to offset {} without an entry",
+                    offset * 4
+                ),
+            );
+            return;
+        };
+        if !visited.insert(offset) {
+            report.add(
+                Severity::Error,
+                path,
+                format!("directory tree links back to offset {}", offset * 4),
+            );
+            return;
+        }
+        for child in [entry.left_offset, entry.right_offset] {
+            if child != 0 {
+                stack.push(child as u32);
+            }
+        }
+        preorder.push(offset);
+    }
+    if preorder.len() < entries.len() {
+        report.add(
+            Severity::Error,
+            path,
+            format!(
+                "{} of {} entries are not linked into the directory tree",
+                entries.len() - preorder.len(),
+                entries.len()
+            ),
+        );
+    }
+
+    // children come after their parent in pre-order
+    let mut heights: HashMap<u32, u32> = HashMap::new();
+    let mut unbalanced = 0;
+    for offset in preorder.iter().rev() {
+        let entry = nodes[offset];
+        let height = |child: u16| heights.get(&(child as u32)).copied().unwrap_or(0);
+        let (left_height, right_height) = (height(entry.left_offset), height(entry.right_offset));
+        if left_height.abs_diff(right_height) > 1 {
+            unbalanced += 1;
+        }
+        heights.insert(*offset, left_height.max(right_height) + 1);
+    }
+    if unbalanced > 0 {
+        report.add(
+            Severity::Warning,
+            path,
+            format!(
+                "directory tree is not balanced at {} of {} entries",
+                unbalanced,
+                preorder.len()
+            ),
+        );
+    }
+
+    // every entry is reached once, the links form a tree
+    let mut previous: Option<&str> = None;
+    let mut stack = Vec::new();
+    let mut current = Some(0_u32);
+    loop {
+        while let Some(offset) = current {
+            stack.push(offset);
+            current = Some(nodes[&offset].left_offset as u32).filter(|&left| left != 0);
+        }
+        let Some(offset) = stack.pop() else {
+            break;
+        };
+        let entry = nodes[&offset];
+        let is_ordered =
+            |previous: &&str| previous.to_ascii_uppercase() < entry.name.to_ascii_uppercase();
+        if let Some(previous) = previous.filter(|previous| !is_ordered(previous)) {
+            report.add(
+                Severity::Error,
+                path,
+                format!(
+                    "directory tree is out of order, {:?} is sorted before {:?}",
+                    previous, entry.name
+                ),
+            );
+            break;
+        }
+        previous = Some(&entry.name);
+        current = Some(entry.right_offset as u32).filter(|&right| right != 0);
+    }
+}
+
+fn check_name(entry: &DirEntry, path: &str, report: &mut CheckReport) {
+    let name = &entry.name;
+    if name.is_empty() {
+        report.add(Severity::Error, path, "empty name".to_string());
+    } else if name == "." || name == ".." {
+        report.add(Severity::Error, path, "reserved name".to_string());
+    } else if name
+        .chars()
+        .any(|c| c.is_control() || c == '/' || c == '\\' || c == char::REPLACEMENT_CHARACTER)
+    {
+        report.add(
+            Severity::Error,
+            path,
+            "name contains control characters, slashes or invalid bytes".to_string(),
+        );
+    } else if !name.is_ascii() {
+        report.add(
+            Severity::Info,
+            path,
+            "name contains non-ASCII characters".to_string(),
+        );
+    }
+    if name.len() > MAX_NAME_LEN {
+        report.add(
+            Severity::Warning,
+            path,
+            format!(
+                "name is {} characters long, the Xbox accepts {}",
+                name.len(),
+                MAX_NAME_LEN
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use super::*;
+    use crate::{
+        entry::{ParseStrategy, ENTRY_HEADER_SIZE},
+        writer::{self, Node},
+    };
+
+    /// Image with files of the given sizes in the root directory, filled with 0xab
+    fn image(files: &[(&str, u32)]) -> Vec<u8> {
+        let mut root: Vec<Node<()>> = files
+            .iter()
+            .map(|&(name, size)| Node::file(name.to_string(), size, ()))
+            .collect();
+        let mut image = Vec::new();
+        writer::write_image(&mut image, &mut root, 0, |node, image| {
+            image
+                .write_all(&vec![0xab; node.size as usize])
+                .map_err(|e| Error::io("Error writing image", e))
+        })
+        .unwrap();
+        image
+    }
+
+    /// Position of the directory entry with the given name in the image
+    fn record(image: &[u8], name: &str) -> usize {
+        let mut pattern = vec![name.len() as u8];
+        pattern.extend_from_slice(name.as_bytes());
+        let name_position = image
+            .windows(pattern.len())
+            .position(|window| window == pattern)
+            .unwrap();
+        name_position + 1 - ENTRY_HEADER_SIZE as usize
+    }
+
+    fn set_u16(image: &mut [u8], position: usize, value: u16) {
+        image[position..position + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_u32(image: &mut [u8], position: usize, value: u32) {
+        image[position..position + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn check(image: Vec<u8>, strategy: ParseStrategy) -> CheckReport {
+        XIso::from_reader_with(Cursor::new(image), strategy)
+            .unwrap()
+            .check()
+            .unwrap()
+    }
+
+    fn has_finding(report: &CheckReport, severity: Severity, message: &str) -> bool {
+        report
+            .findings
+            .iter()
+            .any(|f| f.severity == severity && f.message.contains(message))
+    }
+
+    const SEVEN_FILES: [(&str, u32); 7] = [
+        ("a.bin", 3000),
+        ("b.bin", 3000),
+        ("c.bin", 3000),
+        ("d.bin", 3000),
+        ("e.bin", 3000),
+        ("f.bin", 3000),
+        ("g.bin", 3000),
+    ];
+
+    #[test]
+    fn written_image_is_clean() {
+        for strategy in [ParseStrategy::Tree, ParseStrategy::Scan] {
+            let report = check(image(&SEVEN_FILES), strategy);
+            assert!(report.findings.is_empty(), "{:?}", report.findings);
+            assert_eq!(report.files, 7);
+        }
+    }
+
+    #[test]
+    fn out_of_order_tree() {
+        // "c" is the root with "b" on its left, renamed to "e" it sorts after "c"
+        let mut image = image(&[("b.bin", 1), ("c.bin", 1), ("d.bin", 1)]);
+        let b = record(&image, "b.bin");
+        image[b + ENTRY_HEADER_SIZE as usize] = b'e';
+
+        let report = check(image, ParseStrategy::Tree);
+        assert!(has_finding(&report, Severity::Error, "out of order"));
+    }
+
+    #[test]
+    fn unbalanced_tree() {
+        // "d" is the root, "f" the right child with "e" and "g" below it.
+        // Relinked as the chain d -> e -> f -> g the order is kept.
+        let mut image = image(&SEVEN_FILES);
+        let table = record(&image, "d.bin");
+        let [e, f] = ["e.bin", "f.bin"].map(|name| record(&image, name));
+        let link = |position: usize| ((position - table) / 4) as u16;
+        set_u16(&mut image, table + 2, link(e));
+        set_u16(&mut image, e + 2, link(f));
+        set_u16(&mut image, f, 0);
+
+        let report = check(image, ParseStrategy::Tree);
+        assert!(has_finding(
+            &report,
+            Severity::Warning,
+            "not balanced at 1 of 7"
+        ));
+        assert!(report.is_ok(), "{:?}", report.findings);
+    }
+
+    #[test]
+    fn entry_not_linked_into_tree() {
+        // unlinking "d" from the root "c" hides it from the tree parser
+        let mut image = image(&[("b.bin", 1), ("c.bin", 1), ("d.bin", 1)]);
+        let c = record(&image, "c.bin");
+        set_u16(&mut image, c + 2, 0);
+
+        let report = check(image.clone(), ParseStrategy::Tree);
+        assert_eq!(report.files, 2);
+        let report = check(image, ParseStrategy::Scan);
+        assert!(has_finding(
+            &report,
+            Severity::Error,
+            "1 of 3 entries are not linked"
+        ));
+    }
+
+    #[test]
+    fn overlapping_files() {
+        let mut image = image(&[("a.bin", 3000), ("b.bin", 3000)]);
+        let a_sector = u32::from_le_bytes(
+            image[record(&image, "a.bin") + 4..][..4]
+                .try_into()
+                .unwrap(),
+        );
+        let b = record(&image, "b.bin");
+        set_u32(&mut image, b + 4, a_sector);
+
+        let report = check(image, ParseStrategy::Tree);
+        assert!(has_finding(&report, Severity::Error, "overlap with"));
+    }
+
+    #[test]
+    fn file_beyond_end_of_image() {
+        let mut image = image(&[("a.bin", 3000), ("b.bin", 3000)]);
+        image.truncate(image.len() - SECTOR_SIZE as usize);
+
+        let report = check(image, ParseStrategy::Tree);
+        assert!(has_finding(
+            &report,
+            Severity::Error,
+            "beyond the end of the image"
+        ));
+    }
+
+    #[test]
+    fn file_at_last_sector_does_not_overflow() {
+        let mut image = image(&[("a.bin", 3000), ("b.bin", 3000)]);
+        let a = record(&image, "a.bin");
+        set_u32(&mut image, a + 4, u32::MAX);
+
+        let report = check(image, ParseStrategy::Tree);
+        let message = format!("sectors {}-{} extend", u32::MAX, u32::MAX as u64 + 1);
+        assert!(has_finding(&report, Severity::Error, &message));
+    }
+
+    #[test]
+    fn data_in_gap_between_files() {
+        // "a" takes two sectors, shrunk to one the second one is a gap full of data
+        let mut image = image(&[("a.bin", 3000), ("b.bin", 3000)]);
+        let a = record(&image, "a.bin");
+        set_u32(&mut image, a + 8, 1000);
+
+        let report = check(image, ParseStrategy::Tree);
+        assert!(has_finding(&report, Severity::Warning, "1 of the sectors"));
+        assert!(report.is_ok(), "{:?}", report.findings);
+    }
+}
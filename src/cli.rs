@@ -1,4 +1,4 @@
-use clap::{Args, Parser};
+use clap::{Args, Parser, ValueEnum};
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -11,11 +11,69 @@ pub struct Cli {
     #[arg(short, long)]
     pub skip_update: bool,
 
-    /// Path to the ISO file
+    /// Path to the ISO file, or the source directory when creating one
     #[arg(name = "iso")]
     pub input: PathBuf,
 
-    /// Output directory or FTP url to extract content to
+    /// List content as tree with the number of files and size of every directory
+    #[arg(long, requires = "list", conflicts_with_all = ["du", "format"])]
+    pub tree: bool,
+
+    /// List the total size and number of files of every directory
+    #[arg(long, requires = "list", conflicts_with = "format")]
+    pub du: bool,
+
+    /// Output format of the listing
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = FormatArg::Text)]
+    pub format: FormatArg,
+
+    /// How directory tables are read, scan finds the entries of tables with damaged links
+    #[arg(long, value_enum, value_name = "PARSER", default_value_t = ParserArg::Tree)]
+    pub parser: ParserArg,
+
+    /// Only extract entries matching the glob pattern, e.g. 'media/**' (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    pub include: Vec<String>,
+
+    /// Skip entries matching the glob pattern, e.g. '*.xmv' (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+
+    /// Number of parallel connections used when extracting to a server
+    #[arg(short, long, value_name = "N", default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..))]
+    pub jobs: u16,
+
+    /// How FTP data connections are established
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = FtpModeArg::Passive)]
+    pub ftp_mode: FtpModeArg,
+
+    /// Timeout in seconds for connecting to the FTP or SFTP server
+    #[arg(long, value_name = "SECS")]
+    pub connect_timeout: Option<u64>,
+
+    /// Timeout in seconds for replies of the FTP or SFTP server and stalled transfers
+    #[arg(long, value_name = "SECS")]
+    pub timeout: Option<u64>,
+
+    /// Interval in seconds of TCP keepalive probes on idle FTP and SFTP connections
+    #[arg(long, value_name = "SECS")]
+    pub keepalive: Option<u64>,
+
+    /// How often to reconnect to the FTP or SFTP server when the connection drops during a file
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    pub reconnect: u32,
+
+    /// Trust an SFTP server missing from ~/.ssh/known_hosts and add its host key there
+    #[arg(long)]
+    pub accept_new_host_key: bool,
+
+    /// Logiqx XML DAT file with known good dumps, e.g. from redump.org
+    #[arg(long, value_name = "DAT")]
+    pub dat: Option<PathBuf>,
+
+    /// Output directory, archive (.tar, .tar.zst, .zip) or ftp://, ftps:// or sftp:// url
+    /// to extract content to, path of the created ISO file or base path of the hash files
     #[arg(short, long)]
     pub out: Option<String>,
 }
@@ -30,4 +88,72 @@ pub struct Mode {
     /// List content of the ISO file
     #[arg(short, long)]
     pub list: bool,
+
+    /// Extract content of a damaged ISO file, writing unreadable data as zeros and
+    /// reporting the affected files
+    #[arg(long)]
+    pub salvage: bool,
+
+    /// Create an ISO file from the content of a directory
+    #[arg(short, long)]
+    pub create: bool,
+
+    /// Rewrite the ISO file keeping only the game partition
+    #[arg(short, long)]
+    pub rewrite: bool,
+
+    /// Mount the ISO file read-only at the output directory
+    #[cfg(all(unix, feature = "fuse"))]
+    #[arg(short, long)]
+    pub mount: bool,
+
+    /// Verify previously extracted content of the ISO file at the output location
+    #[arg(short, long)]
+    pub verify: bool,
+
+    /// Write CRC32, MD5 and SHA-1 of all files to <output>.sfv, .md5 and .sha1 and show
+    /// the hashes of the image
+    #[arg(short = 'H', long)]
+    pub hash: bool,
+
+    /// Look up the hashes of the ISO file in the DAT file given with --dat
+    #[arg(long, requires = "dat")]
+    pub validate: bool,
+
+    /// Check the structure of the ISO file: file locations, directory trees, names and
+    /// padding
+    #[arg(long)]
+    pub check: bool,
+
+    /// Show image layout and volume information
+    #[arg(short, long)]
+    pub info: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum FtpModeArg {
+    /// Passive mode (PASV)
+    Passive,
+    /// Extended passive mode (EPSV)
+    Epsv,
+    /// Active mode (PORT), the server connects back
+    Active,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ParserArg {
+    /// Follow the binary tree of every directory table
+    Tree,
+    /// Read every directory table sector by sector
+    Scan,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormatArg {
+    /// File paths
+    Text,
+    /// All entries with size, location and attributes as JSON array
+    Json,
+    /// All entries with size, location and attributes as CSV
+    Csv,
 }
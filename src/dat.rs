@@ -0,0 +1,449 @@
+use std::{
+    fmt,
+    io::{Read, Seek},
+    path::Path,
+};
+
+use crate::{entry::DirEntry, hash::Hashes, meta::SECTOR_SIZE, DiscLayout, Error, XIso};
+
+/// Dump entries of a Logiqx XML DAT file as published by redump.org
+#[derive(Debug, Default)]
+pub struct Dat {
+    /// Name from the header, e.g. "Microsoft - Xbox 360"
+    pub name: String,
+    pub games: Vec<DatGame>,
+}
+
+#[derive(Debug)]
+pub struct DatGame {
+    pub name: String,
+    pub description: String,
+    pub roms: Vec<DatRom>,
+}
+
+/// Expected size and hashes of a file, missing hashes are `None`.
+/// Hashes are lowercase hex strings.
+#[derive(Debug)]
+pub struct DatRom {
+    pub name: String,
+    pub size: Option<u64>,
+    pub crc: Option<String>,
+    pub md5: Option<String>,
+    pub sha1: Option<String>,
+}
+
+impl DatRom {
+    /// Compares the strongest hash both sides have, and the size if the DAT lists it
+    fn matches(&self, hashes: &Hashes) -> bool {
+        if self.size.is_some_and(|size| size != hashes.size) {
+            return false;
+        }
+        if let Some(sha1) = &self.sha1 {
+            return *sha1 == hashes.sha1_hex();
+        }
+        if let Some(md5) = &self.md5 {
+            return *md5 == hashes.md5_hex();
+        }
+        if let Some(crc) = &self.crc {
+            return *crc == hashes.crc32_hex();
+        }
+        false
+    }
+}
+
+impl Dat {
+    pub fn from_path(path: &Path) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::io(format!("Error reading DAT file {:?}", path), e))?;
+        Dat::parse(&content)
+            .map_err(|e| Error::Invalid(format!("Error parsing DAT file {:?}: {}", path, e)))
+    }
+
+    /// Parses the XML of a DAT file. Both `game` and the newer `machine` elements
+    /// are accepted.
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        // DATs reference the Logiqx DTD in their doctype
+        let options = roxmltree::ParsingOptions {
+            allow_dtd: true,
+            ..Default::default()
+        };
+        let document = roxmltree::Document::parse_with_options(content, options)
+            .map_err(|e| Error::Invalid(e.to_string()))?;
+        let root = document.root_element();
+        if !root.has_tag_name("datafile") {
+            return Err(Error::Invalid(format!(
+                "unexpected root element <{}>",
+                root.tag_name().name()
+            )));
+        }
+
+        let mut dat = Dat::default();
+        for node in root.children().filter(|n| n.is_element()) {
+            match node.tag_name().name() {
+                "header" => {
+                    dat.name = child_text(&node, "name");
+                }
+                "game" | "machine" => {
+                    let roms = node
+                        .children()
+                        .filter(|n| n.has_tag_name("rom"))
+                        .map(|rom| DatRom {
+                            name: rom.attribute("name").unwrap_or_default().to_string(),
+                            size: rom.attribute("size").and_then(|s| s.parse().ok()),
+                            crc: hex_attribute(&rom, "crc"),
+                            md5: hex_attribute(&rom, "md5"),
+                            sha1: hex_attribute(&rom, "sha1"),
+                        })
+                        .collect();
+                    dat.games.push(DatGame {
+                        name: node.attribute("name").unwrap_or_default().to_string(),
+                        description: child_text(&node, "description"),
+                        roms,
+                    });
+                }
+                _ => {}
+            }
+        }
+        Ok(dat)
+    }
+
+    /// Returns the game and file with the given size and hashes
+    pub fn find(&self, hashes: &Hashes) -> Option<(&DatGame, &DatRom)> {
+        self.games.iter().find_map(|game| {
+            let rom = game.roms.iter().find(|rom| rom.matches(hashes))?;
+            Some((game, rom))
+        })
+    }
+}
+
+fn child_text(node: &roxmltree::Node, name: &str) -> String {
+    node.children()
+        .find(|n| n.has_tag_name(name))
+        .and_then(|n| n.text())
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+fn hex_attribute(node: &roxmltree::Node, name: &str) -> Option<String> {
+    node.attribute(name)
+        .filter(|value| !value.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// How the image relates to an original disc dump
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DumpKind {
+    /// Complete disc layout including the padding after the game data
+    Full,
+    /// Disc layout cut off after the last used sector of the game partition
+    Trimmed,
+    /// Game partition only, e.g. extracted or created by xiso tools
+    Rebuilt,
+}
+
+impl fmt::Display for DumpKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DumpKind::Full => write!(f, "full disc image"),
+            DumpKind::Trimmed => write!(f, "trimmed disc image"),
+            DumpKind::Rebuilt => write!(f, "rebuilt image (game partition only)"),
+        }
+    }
+}
+
+/// Title and file name of a DAT entry
+#[derive(Debug, Clone)]
+pub struct DatMatch {
+    pub title: String,
+    pub rom: String,
+}
+
+/// Result of looking up the image in a DAT file
+#[derive(Debug)]
+pub struct Validation {
+    pub kind: DumpKind,
+    pub image: Hashes,
+    pub partition: Hashes,
+    /// Entry matching the complete image, i.e. a verified good dump
+    pub image_match: Option<DatMatch>,
+    /// Entry matching the game partition alone, for DATs listing rebuilt images
+    pub partition_match: Option<DatMatch>,
+}
+
+impl Validation {
+    pub fn is_known(&self) -> bool {
+        self.image_match.is_some() || self.partition_match.is_some()
+    }
+}
+
+impl<R: Read + Seek> XIso<R> {
+    /// Hashes the image and looks it up in the DAT. The game partition is looked up
+    /// separately, trimmed and rebuilt images differ from the original dump.
+    pub fn validate(&mut self, dat: &Dat) -> Result<Validation, Error> {
+        let (image, partition) = self.hash_image()?;
+        let kind = self.dump_kind(image.size);
+
+        let to_match = |(game, rom): (&DatGame, &DatRom)| DatMatch {
+            title: if game.description.is_empty() {
+                game.name.clone()
+            } else {
+                game.description.clone()
+            },
+            rom: rom.name.clone(),
+        };
+        let image_match = dat.find(&image).map(to_match);
+        let partition_match = match self.meta.root_offset {
+            // the partition is the whole image
+            0 => None,
+            _ => dat.find(&partition).map(to_match),
+        };
+
+        println!(
+            "Image:     {} bytes, SHA-1 {}",
+            image.size,
+            image.sha1_hex()
+        );
+        println!(
+            "Partition: {} bytes, SHA-1 {}",
+            partition.size,
+            partition.sha1_hex()
+        );
+        println!("Layout:    {}, {}", self.meta.layout, kind);
+        match (&image_match, &partition_match) {
+            (Some(found), _) => println!("Verified:  {} ({})", found.title, found.rom),
+            (None, Some(found)) => {
+                println!("Game partition matches: {} ({})", found.title, found.rom)
+            }
+            (None, None) => {
+                println!("No match in DAT {:?}", dat.name);
+                if kind != DumpKind::Full {
+                    println!(
+                        "DATs list complete disc dumps, a {} can not match them",
+                        kind
+                    );
+                }
+            }
+        }
+
+        Ok(Validation {
+            kind,
+            image,
+            partition,
+            image_match,
+            partition_match,
+        })
+    }
+
+    /// Tells rebuilt from original layouts and detects disc images ending right after
+    /// the game data
+    fn dump_kind(&self, image_size: u64) -> DumpKind {
+        if self.meta.layout == DiscLayout::XIso {
+            return DumpKind::Rebuilt;
+        }
+        let root_end =
+            self.meta.root_dir_sector as u64 * SECTOR_SIZE as u64 + self.meta.root_dir_size as u64;
+        let data_end = data_end(&self.root).max(root_end);
+        let data_end = data_end.div_ceil(SECTOR_SIZE as u64) * SECTOR_SIZE as u64;
+        if image_size <= self.meta.root_offset + data_end {
+            DumpKind::Trimmed
+        } else {
+            DumpKind::Full
+        }
+    }
+}
+
+/// End of the last sector used by file data or directory tables, relative to the
+/// start of the game partition
+fn data_end(entries: &[DirEntry]) -> u64 {
+    entries
+        .iter()
+        .map(|entry| {
+            let end = entry.sector as u64 * SECTOR_SIZE as u64 + entry.size as u64;
+            let subdir_end = entry.subdir.as_deref().map(data_end).unwrap_or(0);
+            end.max(subdir_end)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use super::*;
+    use crate::{
+        hash::Hasher,
+        writer::{self, Node},
+    };
+
+    fn hashes(data: &[u8]) -> Hashes {
+        let mut hasher = Hasher::default();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    fn rom(size: Option<u64>, crc: Option<&str>, md5: Option<&str>, sha1: Option<&str>) -> DatRom {
+        DatRom {
+            name: "game.iso".to_string(),
+            size,
+            crc: crc.map(str::to_string),
+            md5: md5.map(str::to_string),
+            sha1: sha1.map(str::to_string),
+        }
+    }
+
+    /// Rebuilt image with a single file
+    fn image() -> Vec<u8> {
+        let mut root = vec![Node::file("default.xex".to_string(), 3000, ())];
+        let mut image = Vec::new();
+        writer::write_image(&mut image, &mut root, 0, |node, image| {
+            image
+                .write_all(&vec![0xab; node.size as usize])
+                .map_err(|e| Error::io("Error writing image", e))
+        })
+        .unwrap();
+        image
+    }
+
+    #[test]
+    fn parse_game_and_machine_entries() {
+        let dat = Dat::parse(
+            r#"<?xml version="1.0"?>
+            <!DOCTYPE datafile PUBLIC "-//Logiqx//DTD ROM Management Datafile//EN" "http://www.logiqx.com/Dats/datafile.dtd">
+            <datafile>
+                <header><name> Microsoft - Xbox </name></header>
+                <game name="First">
+                    <description>First (USA)</description>
+                    <rom name="First.iso" size="1024" crc="DEADBEEF" md5="" sha1="0123456789ABCDEF0123456789ABCDEF01234567"/>
+                </game>
+                <machine name="Second">
+                    <rom name="Second.iso" size="unknown" md5="0123456789abcdef0123456789abcdef"/>
+                </machine>
+                <resource name="Ignored"/>
+            </datafile>"#,
+        )
+        .unwrap();
+
+        assert_eq!(dat.name, "Microsoft - Xbox");
+        assert_eq!(dat.games.len(), 2);
+        let first = &dat.games[0];
+        assert_eq!(first.name, "First");
+        assert_eq!(first.description, "First (USA)");
+        assert_eq!(first.roms[0].name, "First.iso");
+        assert_eq!(first.roms[0].size, Some(1024));
+        assert_eq!(first.roms[0].crc.as_deref(), Some("deadbeef"));
+        assert_eq!(first.roms[0].md5, None);
+        assert_eq!(
+            first.roms[0].sha1.as_deref(),
+            Some("0123456789abcdef0123456789abcdef01234567")
+        );
+        let second = &dat.games[1];
+        assert_eq!(second.name, "Second");
+        assert_eq!(second.description, "");
+        assert_eq!(second.roms[0].size, None);
+        assert_eq!(second.roms[0].crc, None);
+
+        assert!(matches!(
+            Dat::parse("<softwarelist/>"),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn strongest_hash_decides() {
+        let data = hashes(b"game data");
+        let (crc, md5, sha1) = (data.crc32_hex(), data.md5_hex(), data.sha1_hex());
+        let (crc, md5, sha1) = (Some(crc.as_str()), Some(md5.as_str()), Some(sha1.as_str()));
+        let wrong_crc = Some("00000000");
+        let wrong_md5 = Some("00000000000000000000000000000000");
+        let wrong_sha1 = Some("0000000000000000000000000000000000000000");
+
+        assert!(rom(None, crc, md5, sha1).matches(&data));
+        assert!(rom(None, wrong_crc, wrong_md5, sha1).matches(&data));
+        assert!(!rom(None, crc, md5, wrong_sha1).matches(&data));
+        assert!(rom(None, wrong_crc, md5, None).matches(&data));
+        assert!(!rom(None, crc, wrong_md5, None).matches(&data));
+        assert!(rom(None, crc, None, None).matches(&data));
+        assert!(!rom(None, wrong_crc, None, None).matches(&data));
+        assert!(!rom(None, None, None, None).matches(&data));
+    }
+
+    #[test]
+    fn size_must_match() {
+        let data = hashes(b"game data");
+        let sha1 = data.sha1_hex();
+        assert!(rom(Some(9), None, None, Some(&sha1)).matches(&data));
+        assert!(!rom(Some(10), None, None, Some(&sha1)).matches(&data));
+    }
+
+    #[test]
+    fn find_returns_game_and_rom() {
+        let data = hashes(b"game data");
+        let dat = Dat {
+            name: String::new(),
+            games: vec![
+                DatGame {
+                    name: "Other".to_string(),
+                    description: String::new(),
+                    roms: vec![rom(None, Some("00000000"), None, None)],
+                },
+                DatGame {
+                    name: "Game".to_string(),
+                    description: String::new(),
+                    roms: vec![rom(Some(9), Some(&data.crc32_hex()), None, None)],
+                },
+            ],
+        };
+        let (game, rom) = dat.find(&data).unwrap();
+        assert_eq!(game.name, "Game");
+        assert_eq!(rom.name, "game.iso");
+        assert!(dat.find(&hashes(b"other data")).is_none());
+    }
+
+    #[test]
+    fn dump_kind_of_layouts() {
+        let image = image();
+        let partition_size = image.len() as u64;
+        let mut xiso = XIso::from_reader(Cursor::new(image)).unwrap();
+        assert_eq!(xiso.dump_kind(partition_size), DumpKind::Rebuilt);
+
+        // the same partition inside a disc layout
+        xiso.meta.layout = DiscLayout::Xgd2;
+        xiso.meta.root_offset = 0x1000_0000;
+        let data_end = xiso.meta.root_offset + partition_size;
+        assert_eq!(xiso.dump_kind(data_end), DumpKind::Trimmed);
+        assert_eq!(
+            xiso.dump_kind(data_end + SECTOR_SIZE as u64),
+            DumpKind::Full
+        );
+    }
+
+    #[test]
+    fn validate_rebuilt_image() {
+        let image = image();
+        let data = hashes(&image);
+        let dat = Dat::parse(&format!(
+            r#"<datafile>
+                <game name="Game"><description>Game (World)</description>
+                    <rom name="Game.iso" size="{}" sha1="{}"/>
+                </game>
+            </datafile>"#,
+            data.size,
+            data.sha1_hex().to_uppercase()
+        ))
+        .unwrap();
+
+        let validation = XIso::from_reader(Cursor::new(image))
+            .unwrap()
+            .validate(&dat)
+            .unwrap();
+        assert_eq!(validation.kind, DumpKind::Rebuilt);
+        assert_eq!(validation.image, data);
+        let found = validation.image_match.unwrap();
+        assert_eq!(found.title, "Game (World)");
+        assert_eq!(found.rom, "Game.iso");
+        // the partition is the whole image
+        assert!(validation.partition_match.is_none());
+    }
+}
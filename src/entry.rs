@@ -1,6 +1,19 @@
-use crate::meta::{IsoMeta, SECTOR_SIZE};
+use crate::{
+    meta::{IsoMeta, SECTOR_SIZE},
+    salvage::Damage,
+    Error,
+};
+use binrw::{BinRead, BinReaderExt};
+use std::collections::HashSet;
 use std::io::{Read, Seek, SeekFrom};
-use binrw::BinRead;
+
+/// Attribute flags of directory entries, the same as the FAT ones
+pub(crate) const ATTR_READ_ONLY: u8 = 0x01;
+pub(crate) const ATTR_HIDDEN: u8 = 0x02;
+pub(crate) const ATTR_SYSTEM: u8 = 0x04;
+pub(crate) const ATTR_DIRECTORY: u8 = 0x10;
+pub(crate) const ATTR_ARCHIVE: u8 = 0x20;
+pub(crate) const ATTR_NORMAL: u8 = 0x80;
 
 #[derive(Debug, Eq, Ord, PartialEq, PartialOrd, Clone, BinRead)]
 #[br(little)]
@@ -11,128 +24,469 @@ pub struct DirEntry {
     pub size: u32,
     pub attributes: u8,
     name_len: u8,
-    #[br(count = name_len)]
+    #[br(count = usize::from(name_len))]
     #[br(map = |s: Vec<u8>|String::from_utf8_lossy(&s).to_string(), align_after = 4)]
     pub name: String,
+    /// Byte offset of the entry in its directory table. The left and right
+    /// offsets count in units of 4 bytes.
+    #[br(ignore)]
+    pub table_offset: u32,
     #[br(ignore)]
     pub subdir: Option<Vec<DirEntry>>,
 }
 
 impl DirEntry {
-    #[cfg(feature = "alt_parser")]
-    pub fn parse<R: Read + Seek>(
-        reader: &mut R,
-        iso_meta: &IsoMeta,
-    ) -> Result<Option<DirEntry>, binrw::Error> {
-        let mut record = DirEntry::read(reader)?;
-
-        if record.left_offset == 0xffff || record.right_offset == 0xffff {
-            println!("{} - dir empty?", &record.name);
-            return Ok(None);
-        }
-
-        record.subdir = if record.is_dir() {
-            let cur_pos = reader.stream_position()?;
-            let subdir = parse_dir(reader, &iso_meta, record.size, record.sector)?;
-            reader.seek(SeekFrom::Start(cur_pos))?;
-            Some(subdir)
-        } else {
-            None
-        };
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY == ATTR_DIRECTORY
+    }
 
-        Ok(Some(record))
+    pub fn is_read_only(&self) -> bool {
+        self.attributes & ATTR_READ_ONLY == ATTR_READ_ONLY
     }
 
-    pub fn is_dir(&self) -> bool {
-        self.attributes & 0x10 == 0x10
+    pub fn is_hidden(&self) -> bool {
+        self.attributes & ATTR_HIDDEN == ATTR_HIDDEN
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.attributes & ATTR_SYSTEM == ATTR_SYSTEM
+    }
+
+    pub fn is_archive(&self) -> bool {
+        self.attributes & ATTR_ARCHIVE == ATTR_ARCHIVE
+    }
+
+    pub fn is_normal(&self) -> bool {
+        self.attributes & ATTR_NORMAL == ATTR_NORMAL
+    }
+
+    /// Names of the set attribute flags, e.g. `["hidden", "archive"]`
+    pub fn attribute_names(&self) -> Vec<&'static str> {
+        [
+            (self.is_dir(), "directory"),
+            (self.is_hidden(), "hidden"),
+            (self.is_read_only(), "read-only"),
+            (self.is_system(), "system"),
+            (self.is_archive(), "archive"),
+            (self.is_normal(), "normal"),
+        ]
+        .into_iter()
+        .filter_map(|(set, name)| set.then_some(name))
+        .collect()
     }
 }
 
-#[cfg(feature = "alt_parser")]
-fn parse_dir<R: Read + Seek>(
-    reader: &mut R,
-    iso_meta: &IsoMeta,
-    size: u32,
+/// Looks up an entry by its slash separated path, ignoring case like XDVDFS does
+pub fn find_entry<'a>(entries: &'a [DirEntry], path: &str) -> Option<&'a DirEntry> {
+    let mut entries = entries;
+    let mut found = None;
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        let entry = entries
+            .iter()
+            .find(|e| e.name.eq_ignore_ascii_case(segment))?;
+        entries = entry.subdir.as_deref().unwrap_or_default();
+        found = Some(entry);
+    }
+    found
+}
+
+/// How the directory tables of an image are read
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ParseStrategy {
+    /// Follows the binary tree of every directory table from its first entry
+    #[default]
+    Tree,
+    /// Reads every directory table sector by sector, independent of the tree
+    /// links. Finds the entries of tables with damaged links.
+    Scan,
+}
+
+/// Size of a directory entry without its name
+pub(crate) const ENTRY_HEADER_SIZE: u32 = 14;
+/// Longest name the Xbox file systems accept
+pub(crate) const MAX_NAME_LEN: usize = 42;
+/// Deepest directory nesting accepted, real images stay far below it
+const MAX_DEPTH: usize = 64;
+
+/// Directory table waiting to be parsed, with the entry it belongs to
+struct PendingDir {
     sector: u32,
-) -> Result<Vec<DirEntry>, binrw::Error> {
-    let mut entries = Vec::<DirEntry>::new();
+    size: u32,
+    path: String,
+    depth: usize,
+    /// Index of the parent table and of the directory entry in it
+    parent: Option<(usize, usize)>,
+}
 
-    let mut sector_count = size / SECTOR_SIZE;
-    if size % SECTOR_SIZE > 0 {
-        sector_count += 1;
+impl PendingDir {
+    fn display_path(&self) -> &str {
+        if self.path.is_empty() {
+            "/"
+        } else {
+            &self.path
+        }
     }
+}
 
-    for i in 0..sector_count {
-        let sector_position = ((sector + i) as u64) * (SECTOR_SIZE as u64) + iso_meta.root_offset;
-        reader.seek(std::io::SeekFrom::Start(sector_position))?;
+/// Reads the entries of one directory table by walking its binary tree with an
+/// explicit stack. Every offset has to lie inside the table and may only be
+/// visited once.
+fn parse_dir_table<R: Read + Seek>(
+    reader: &mut R,
+    iso_meta: &IsoMeta,
+    dir: &PendingDir,
+    visited: &mut HashSet<(u32, u16)>,
+    entries: &mut Vec<DirEntry>,
+) -> Result<(), Error> {
+    let display_path = dir.display_path();
+    // referencing entry of every offset, for error messages
+    let mut stack: Vec<(u16, Option<String>)> = vec![(0, None)];
+
+    while let Some((offset, referrer)) = stack.pop() {
+        let describe = || match &referrer {
+            Some(name) => format!("entry {:?} in directory {:?}", name, display_path),
+            None => format!("directory {:?}", display_path),
+        };
+        if offset as u32 * 4 + ENTRY_HEADER_SIZE > dir.size {
+            return Err(Error::Corrupt(format!(
+                "The {} points to offset {} outside of the directory table of {} bytes",
+                describe(),
+                offset as u32 * 4,
+                dir.size
+            )));
+        }
+        if !visited.insert((dir.sector, offset)) {
+            return Err(Error::Corrupt(format!(
+                "The {} points back to sector {} offset {}, the directory tables form a loop",
+                describe(),
+                dir.sector,
+                offset as u32 * 4
+            )));
+        }
 
-        while let Some(entry) = DirEntry::parse(reader, iso_meta)? {
-            // TODO duplicates exist, why?
-            let exists = entries.iter().find(|e| entry.name == e.name).is_some();
-            if !exists {
-                entries.push(entry);
+        let position =
+            dir.sector as u64 * SECTOR_SIZE as u64 + iso_meta.root_offset + offset as u64 * 4;
+        let mut record = reader
+            .seek(SeekFrom::Start(position))
+            .map_err(binrw::Error::Io)
+            .and_then(|_| DirEntry::read(reader))
+            .map_err(|e| {
+                Error::parse(
+                    format!(
+                        "Error reading the entry at sector {} offset {} referenced by the {}",
+                        dir.sector,
+                        offset as u32 * 4,
+                        describe()
+                    ),
+                    e,
+                )
+            })?;
+
+        // some tools write tables of empty directories filled with padding
+        if offset == 0 && record.left_offset == 0xffff && record.right_offset == 0xffff {
+            break;
+        }
+        if offset as u32 * 4 + ENTRY_HEADER_SIZE + record.name.len() as u32 > dir.size {
+            return Err(Error::Corrupt(format!(
+                "The entry {:?} in directory {:?} exceeds the directory table of {} bytes",
+                record.name, display_path, dir.size
+            )));
+        }
+
+        for child in [record.left_offset, record.right_offset] {
+            if child != 0 {
+                stack.push((child, Some(record.name.clone())));
             }
         }
+        record.table_offset = offset as u32 * 4;
+        entries.push(record);
     }
-    entries.sort_by_key(|rec| rec.name.to_lowercase());
 
-    Ok(entries)
+    Ok(())
 }
 
+/// Reads the entries of one directory table in the order they are stored.
+/// Entries never span sectors, the rest of a sector after the last entry is
+/// padded with 0xff. Records that do not fit in their sector are skipped the
+/// same way.
+fn scan_dir_table<R: Read + Seek>(
+    reader: &mut R,
+    iso_meta: &IsoMeta,
+    dir: &PendingDir,
+    visited: &mut HashSet<(u32, u16)>,
+    entries: &mut Vec<DirEntry>,
+) -> Result<(), Error> {
+    let display_path = dir.display_path();
+    if !visited.insert((dir.sector, 0)) {
+        return Err(Error::Corrupt(format!(
+            "The directory {:?} points back to sector {}, the directory tables form a loop",
+            display_path, dir.sector
+        )));
+    }
 
-#[cfg(feature = "alt_parser")]
-pub fn parse_root<R: Read + Seek>(reader: &mut R, iso_meta: &IsoMeta) -> Result<Vec<DirEntry>, String> {
-    return parse_dir(
-        reader,
-        iso_meta,
-        iso_meta.root_dir_size,
-        iso_meta.root_dir_sector,
-    )
-    .map_err(|e| format!("Error parsing ISO file: {}", e));
-}
+    let mut offset = 0;
+    while offset + ENTRY_HEADER_SIZE <= dir.size {
+        let sector_end = (offset / SECTOR_SIZE + 1) * SECTOR_SIZE;
+        if offset + ENTRY_HEADER_SIZE > sector_end {
+            offset = sector_end;
+            continue;
+        }
+
+        let position =
+            dir.sector as u64 * SECTOR_SIZE as u64 + iso_meta.root_offset + offset as u64;
+        let read_error = |e| {
+            Error::parse(
+                format!(
+                    "Error reading the directory entry at sector {} offset {} of directory {:?}",
+                    dir.sector, offset, display_path
+                ),
+                e,
+            )
+        };
+        reader
+            .seek(SeekFrom::Start(position))
+            .map_err(|e| read_error(binrw::Error::Io(e)))?;
+        let left_offset: u16 = reader.read_le().map_err(read_error)?;
+        if left_offset == 0xffff {
+            offset = sector_end;
+            continue;
+        }
+        reader
+            .seek(SeekFrom::Start(position))
+            .map_err(|e| read_error(binrw::Error::Io(e)))?;
+        let mut record = DirEntry::read(reader).map_err(read_error)?;
+
+        let entry_end = offset + ENTRY_HEADER_SIZE + record.name.len() as u32;
+        if record.name.is_empty() || entry_end > sector_end || entry_end > dir.size {
+            offset = sector_end;
+            continue;
+        }
+        record.table_offset = offset;
+        offset = entry_end.next_multiple_of(4);
+        entries.push(record);
+    }
 
+    Ok(())
+}
 
-#[cfg(not(feature = "alt_parser"))]
-fn parse_dir_entry<R: Read + Seek>(
+/// Parses all directory tables with the given strategy, without recursion. Tables
+/// are collected in a flat list first and attached to their directory entries
+/// afterwards, children always come after their parent in the list.
+/// With `damage` given, broken tables are recorded there instead of failing, the
+/// entries read up to the failure are kept.
+pub fn parse_root<R: Read + Seek>(
     reader: &mut R,
     iso_meta: &IsoMeta,
-    sector: u32,
-    offset: u16,
-    parent: &mut Vec<DirEntry>,
-) -> Result<(), binrw::Error> {
-    let sector_position =
-        (sector as u64) * (SECTOR_SIZE as u64) + iso_meta.root_offset + offset as u64 * 4;
-    reader.seek(SeekFrom::Start(sector_position))?;
+    strategy: ParseStrategy,
+    mut damage: Option<&mut Vec<Damage>>,
+) -> Result<Vec<DirEntry>, Error> {
+    let mut tables: Vec<Vec<DirEntry>> = Vec::new();
+    let mut parents = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![PendingDir {
+        sector: iso_meta.root_dir_sector,
+        size: iso_meta.root_dir_size,
+        path: String::new(),
+        depth: 0,
+        parent: None,
+    }];
+
+    while let Some(dir) = pending.pop() {
+        let mut entries = Vec::new();
+        let result = match strategy {
+            ParseStrategy::Tree => {
+                parse_dir_table(reader, iso_meta, &dir, &mut visited, &mut entries)
+            }
+            ParseStrategy::Scan => {
+                scan_dir_table(reader, iso_meta, &dir, &mut visited, &mut entries)
+            }
+        };
+        match (result, damage.as_deref_mut()) {
+            (Ok(()), _) => {}
+            (Err(e), Some(damage)) => damage.push(Damage {
+                path: dir.display_path().to_string(),
+                sectors: Damage::sector_range(dir.sector, 0, dir.size as u64),
+                reason: e.report(),
+            }),
+            (Err(e), None) => return Err(e),
+        }
+        entries.sort_by_key(|rec| rec.name.to_lowercase());
+        let index = tables.len();
+        for (position, entry) in entries.iter_mut().enumerate() {
+            if !entry.is_dir() {
+                continue;
+            }
+            let path = format!("{}/{}", dir.path, entry.name);
+            // empty directories have no directory table
+            entry.subdir = Some(Vec::new());
+            if dir.depth + 1 > MAX_DEPTH {
+                let error = Error::Corrupt(format!(
+                    "The directory {:?} is nested deeper than {} levels",
+                    path, MAX_DEPTH
+                ));
+                match damage.as_deref_mut() {
+                    Some(damage) => damage.push(Damage {
+                        path,
+                        sectors: Damage::sector_range(entry.sector, 0, entry.size as u64),
+                        reason: error.report(),
+                    }),
+                    None => return Err(error),
+                }
+            } else if entry.size > 0 {
+                pending.push(PendingDir {
+                    sector: entry.sector,
+                    size: entry.size,
+                    path,
+                    depth: dir.depth + 1,
+                    parent: Some((index, position)),
+                });
+            }
+        }
+        tables.push(entries);
+        parents.push(dir.parent);
+    }
+
+    for index in (1..tables.len()).rev() {
+        let entries = std::mem::take(&mut tables[index]);
+        if let Some((table, position)) = parents[index] {
+            tables[table][position].subdir = Some(entries);
+        }
+    }
+    Ok(tables.swap_remove(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::meta::{get_iso_meta, MAGIC};
 
-    let mut record = DirEntry::read(reader)?;
+    const ROOT_SECTOR: u32 = 33;
+    const STRATEGIES: [ParseStrategy; 2] = [ParseStrategy::Tree, ParseStrategy::Scan];
 
-    record.subdir = if record.is_dir() {
-        let mut subdir = Vec::<DirEntry>::new();
-        parse_dir_entry(reader, &iso_meta, record.sector, 0, &mut subdir)?;
-        Some(subdir)
-    } else {
-        None
-    };
+    fn entry(left: u16, right: u16, sector: u32, size: u32, attributes: u8, name: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&left.to_le_bytes());
+        data.extend_from_slice(&right.to_le_bytes());
+        data.extend_from_slice(&sector.to_le_bytes());
+        data.extend_from_slice(&size.to_le_bytes());
+        data.push(attributes);
+        data.push(name.len() as u8);
+        data.extend_from_slice(name.as_bytes());
+        data.resize(data.len().next_multiple_of(4), 0xff);
+        data
+    }
+
+    /// Image with the root table of one sector at [`ROOT_SECTOR`] and the given
+    /// tables, every table padded to a full sector with 0xff
+    fn image(tables: &[(u32, Vec<u8>)]) -> (Cursor<Vec<u8>>, IsoMeta) {
+        let sector_size = SECTOR_SIZE as usize;
+        let end = tables.iter().map(|(sector, _)| *sector + 1).max().unwrap();
+        let mut data = vec![0; end as usize * sector_size];
+        let header = &mut data[32 * sector_size..33 * sector_size];
+        header[..20].copy_from_slice(MAGIC);
+        header[20..24].copy_from_slice(&ROOT_SECTOR.to_le_bytes());
+        header[24..28].copy_from_slice(&SECTOR_SIZE.to_le_bytes());
+        header[sector_size - 20..].copy_from_slice(MAGIC);
+        for (sector, table) in tables {
+            let start = *sector as usize * sector_size;
+            data[start..start + sector_size].fill(0xff);
+            data[start..start + table.len()].copy_from_slice(table);
+        }
 
-    if record.left_offset != 0 {
-        parse_dir_entry(reader, &iso_meta, sector, record.left_offset, parent)?;
+        let mut reader = Cursor::new(data);
+        let meta = get_iso_meta(&mut reader).unwrap();
+        (reader, meta)
     }
 
-    if record.right_offset != 0 {
-        parse_dir_entry(reader, &iso_meta, sector, record.right_offset, parent)?;
+    fn parse(
+        tables: &[(u32, Vec<u8>)],
+        strategy: ParseStrategy,
+        damage: Option<&mut Vec<Damage>>,
+    ) -> Result<Vec<DirEntry>, Error> {
+        let (mut reader, meta) = image(tables);
+        parse_root(&mut reader, &meta, strategy, damage)
     }
 
-    parent.push(record);
+    fn names(entries: &[DirEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
 
-    Ok(())
-}
+    #[test]
+    fn offset_outside_of_table() {
+        let root = entry(0, 0x300, 40, 1, ATTR_ARCHIVE, "a.txt");
+        let tables = [(ROOT_SECTOR, root)];
+
+        let tree = parse(&tables, ParseStrategy::Tree, None);
+        assert!(matches!(tree, Err(Error::Corrupt(_))), "{:?}", tree);
+        // the scan does not follow the links
+        let scan = parse(&tables, ParseStrategy::Scan, None).unwrap();
+        assert_eq!(names(&scan), ["a.txt"]);
+    }
+
+    #[test]
+    fn entry_linking_to_itself() {
+        // "a" takes 16 bytes, "b" at offset 4 points to itself
+        let root = [
+            entry(0, 4, 40, 1, ATTR_ARCHIVE, "a"),
+            entry(0, 4, 41, 1, ATTR_ARCHIVE, "b"),
+        ]
+        .concat();
+        let tables = [(ROOT_SECTOR, root)];
+
+        let tree = parse(&tables, ParseStrategy::Tree, None);
+        assert!(matches!(tree, Err(Error::Corrupt(_))), "{:?}", tree);
+        let scan = parse(&tables, ParseStrategy::Scan, None).unwrap();
+        assert_eq!(names(&scan), ["a", "b"]);
+    }
+
+    #[test]
+    fn directory_referencing_the_root() {
+        let root = entry(0, 0, ROOT_SECTOR, SECTOR_SIZE, ATTR_DIRECTORY, "loop");
+        let tables = [(ROOT_SECTOR, root)];
 
-#[cfg(not(feature = "alt_parser"))]
-pub fn parse_root<R: Read + Seek>(reader: &mut R, iso_meta: &IsoMeta) -> Result<Vec<DirEntry>, String> {
-    let mut root_dir = Vec::<DirEntry>::new();
-    parse_dir_entry(reader, iso_meta, iso_meta.root_dir_sector, 0, &mut root_dir)
-        .map_err(|e| format!("Error parsing ISO file: {}", e))?;
-    root_dir.sort_by_key(|rec| rec.name.to_lowercase());
+        for strategy in STRATEGIES {
+            let result = parse(&tables, strategy, None);
+            assert!(matches!(result, Err(Error::Corrupt(_))), "{:?}", result);
 
-    Ok(root_dir)
+            let mut damage = Vec::new();
+            let entries = parse(&tables, strategy, Some(&mut damage)).unwrap();
+            assert_eq!(names(&entries), ["loop"]);
+            assert_eq!(damage.len(), 1);
+            assert_eq!(damage[0].path, "/loop");
+        }
+    }
+
+    #[test]
+    fn directories_nested_too_deep() {
+        let tables: Vec<(u32, Vec<u8>)> = (ROOT_SECTOR..ROOT_SECTOR + 70)
+            .map(|sector| {
+                let dir = entry(0, 0, sector + 1, SECTOR_SIZE, ATTR_DIRECTORY, "d");
+                (sector, dir)
+            })
+            .collect();
+
+        for strategy in STRATEGIES {
+            let result = parse(&tables, strategy, None);
+            assert!(matches!(result, Err(Error::Corrupt(_))), "{:?}", result);
+
+            let mut damage = Vec::new();
+            parse(&tables, strategy, Some(&mut damage)).unwrap();
+            assert_eq!(damage.len(), 1);
+            assert_eq!(damage[0].path, "/d".repeat(MAX_DEPTH + 1));
+        }
+    }
+
+    #[test]
+    fn table_of_padding_is_empty() {
+        let root = entry(0, 0, 34, SECTOR_SIZE, ATTR_DIRECTORY, "empty");
+        let tables = [(ROOT_SECTOR, root), (34, Vec::new())];
+
+        for strategy in STRATEGIES {
+            let entries = parse(&tables, strategy, None).unwrap();
+            assert_eq!(names(&entries), ["empty"]);
+            assert_eq!(entries[0].subdir, Some(Vec::new()));
+        }
+    }
 }
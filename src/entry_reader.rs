@@ -0,0 +1,146 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Read and seek handle restricted to the data of a single file inside the image.
+/// Positions are relative to the start of the file and reads stop at its end.
+pub struct EntryReader<'a, R: Read + Seek> {
+    reader: &'a mut R,
+    start: u64,
+    size: u64,
+    position: u64,
+}
+
+impl<'a, R: Read + Seek> EntryReader<'a, R> {
+    pub(crate) fn new(reader: &'a mut R, start: u64, size: u64) -> io::Result<Self> {
+        reader.seek(SeekFrom::Start(start))?;
+        Ok(EntryReader {
+            reader,
+            start,
+            size,
+            position: 0,
+        })
+    }
+
+    /// Size of the file in bytes
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+}
+
+impl<R: Read + Seek> Read for EntryReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.size.saturating_sub(self.position);
+        let count = (buf.len() as u64).min(remaining) as usize;
+        if count == 0 {
+            return Ok(0);
+        }
+        let read = self.reader.read(&mut buf[..count])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for EntryReader<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        };
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(invalid)?;
+        let image_position = self.start.checked_add(position).ok_or_else(invalid)?;
+
+        self.reader.seek(SeekFrom::Start(image_position))?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Image of the bytes 0..100 with an entry covering 10..30
+    fn test_image() -> Cursor<Vec<u8>> {
+        Cursor::new((0..100).collect())
+    }
+
+    #[test]
+    fn reads_stop_at_entry_end() {
+        let mut image = test_image();
+        let mut reader = EntryReader::new(&mut image, 10, 20).unwrap();
+        assert_eq!(reader.len(), 20);
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!(content, (10..30).collect::<Vec<u8>>());
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 0);
+
+        let mut image = test_image();
+        let mut reader = EntryReader::new(&mut image, 10, 20).unwrap();
+        let mut buffer = [0; 16];
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(reader.read(&mut buffer).unwrap(), 4);
+        assert_eq!(buffer[..4], [26, 27, 28, 29]);
+    }
+
+    #[test]
+    fn seek_relative_to_entry() {
+        let mut image = test_image();
+        let mut reader = EntryReader::new(&mut image, 10, 20).unwrap();
+        let mut byte = [0];
+
+        assert_eq!(reader.seek(SeekFrom::Start(5)).unwrap(), 5);
+        reader.read_exact(&mut byte).unwrap();
+        assert_eq!(byte, [15]);
+
+        assert_eq!(reader.seek(SeekFrom::Current(2)).unwrap(), 8);
+        reader.read_exact(&mut byte).unwrap();
+        assert_eq!(byte, [18]);
+        assert_eq!(reader.seek(SeekFrom::Current(-9)).unwrap(), 0);
+
+        assert_eq!(reader.seek(SeekFrom::End(-1)).unwrap(), 19);
+        reader.read_exact(&mut byte).unwrap();
+        assert_eq!(byte, [29]);
+        assert_eq!(reader.read(&mut byte).unwrap(), 0);
+    }
+
+    #[test]
+    fn seek_past_end() {
+        let mut image = test_image();
+        let mut reader = EntryReader::new(&mut image, 10, 20).unwrap();
+        assert_eq!(reader.seek(SeekFrom::End(5)).unwrap(), 25);
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 0);
+        assert_eq!(reader.seek(SeekFrom::Start(1000)).unwrap(), 1000);
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 0);
+    }
+
+    #[test]
+    fn invalid_seeks() {
+        let mut image = test_image();
+        let mut reader = EntryReader::new(&mut image, 10, 20).unwrap();
+        reader.seek(SeekFrom::Start(4)).unwrap();
+        for pos in [
+            SeekFrom::Current(-5),
+            SeekFrom::End(-21),
+            SeekFrom::Start(u64::MAX),
+            SeekFrom::Start(u64::MAX - 9),
+            SeekFrom::End(i64::MIN),
+        ] {
+            let error = reader.seek(pos).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:?}", pos);
+        }
+        // failed seeks keep the position
+        assert_eq!(reader.stream_position().unwrap(), 4);
+    }
+}
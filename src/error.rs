@@ -0,0 +1,203 @@
+use std::io;
+
+use suppaftp::{FtpError, Status};
+
+/// Failure of reading an image or of writing its content, with the underlying
+/// error as [`std::error::Error::source`]
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// No XDVDFS volume descriptor at any of the known partition offsets
+    #[error("Unsupported XISO format")]
+    NotXiso,
+    /// The image ends before data it references
+    #[error("{context}: image is truncated")]
+    Truncated {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    /// Directory tables or other structures of the image can not be read
+    #[error("{context}")]
+    Parse {
+        context: String,
+        #[source]
+        source: binrw::Error,
+    },
+    /// Directory tables point outside of themselves, loop or are nested too deep
+    #[error("{0}")]
+    Corrupt(String),
+    /// Reading or writing local files failed
+    #[error("{context}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    /// The output location has no space left
+    #[error("{context}")]
+    DiskFull {
+        context: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The FTP server refused a command or the connection failed
+    #[error("{context}")]
+    Ftp {
+        context: String,
+        #[source]
+        source: FtpError,
+    },
+    /// The SSH connection or an SFTP operation failed
+    #[error("{context}")]
+    Sftp {
+        context: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// Writing a zip archive failed
+    #[error("{context}")]
+    Zip {
+        context: String,
+        #[source]
+        source: zip::result::ZipError,
+    },
+    /// The server rejected the login
+    #[error("Error logging in to {server} as {user}")]
+    Auth {
+        server: String,
+        user: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// Written or previously extracted content differs from the image
+    #[error("{0}")]
+    Mismatch(String),
+    /// Invalid input like a malformed url or pattern, or an operation the output
+    /// does not support
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl Error {
+    /// Error of local file operations, telling a full disk apart
+    pub(crate) fn io(context: impl Into<String>, source: io::Error) -> Self {
+        let context = context.into();
+        match source.kind() {
+            io::ErrorKind::StorageFull => Error::DiskFull {
+                context,
+                source: Box::new(source),
+            },
+            _ => Error::Io { context, source },
+        }
+    }
+
+    /// Error reading from the image, telling a premature end apart
+    pub(crate) fn image(context: impl Into<String>, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::UnexpectedEof => Error::Truncated {
+                context: context.into(),
+                source,
+            },
+            _ => Error::io(context, source),
+        }
+    }
+
+    /// Error parsing structures of the image
+    pub(crate) fn parse(context: impl Into<String>, source: binrw::Error) -> Self {
+        match source {
+            binrw::Error::Io(source) => Error::image(context, source),
+            binrw::Error::Backtrace(backtrace) => Error::parse(context, *backtrace.error),
+            source => Error::Parse {
+                context: context.into(),
+                source,
+            },
+        }
+    }
+
+    /// Error of an FTP command, telling a full disk of the server apart
+    pub(crate) fn ftp(context: impl Into<String>, source: FtpError) -> Self {
+        let context = context.into();
+        match &source {
+            FtpError::UnexpectedResponse(response)
+                if matches!(
+                    response.status,
+                    Status::RequestedActionNotTaken | Status::ExceededStorage
+                ) =>
+            {
+                Error::DiskFull {
+                    context,
+                    source: Box::new(source),
+                }
+            }
+            _ => Error::Ftp { context, source },
+        }
+    }
+
+    /// Error of the ssh session or of an SFTP operation, or of the underlying connection
+    pub(crate) fn sftp(
+        context: impl Into<String>,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Error::Sftp {
+            context: context.into(),
+            source: source.into(),
+        }
+    }
+
+    /// Whether the connection to the server broke, as opposed to the server refusing
+    /// a command or a local failure. Only then reconnecting can help.
+    pub(crate) fn is_connection_error(&self) -> bool {
+        match self {
+            Error::Ftp {
+                source: FtpError::ConnectionError(_),
+                ..
+            } => true,
+            // writing to or reading from the data stream
+            Error::Io { source, .. } => matches!(
+                source.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::WouldBlock
+            ),
+            Error::Sftp { source, .. } => match source.downcast_ref::<ssh2::Error>() {
+                // libssh2 socket send/recv failures, disconnects and timeouts
+                Some(e) => matches!(
+                    e.code(),
+                    ssh2::ErrorCode::Session(-7 | -9 | -13 | -30 | -43)
+                ),
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Message including the messages of all underlying errors, e.g.
+    /// `Error creating file "out/default.xex": Permission denied (os error 13)`
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            report = format!("{}: {}", report, error);
+            source = error.source();
+        }
+        report
+    }
+
+    /// Process exit code for the class of the error. 2 is used by the argument parser.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Invalid(_) => 1,
+            Error::NotXiso => 3,
+            Error::Truncated { .. } | Error::Parse { .. } | Error::Corrupt(_) => 4,
+            Error::Io { .. } | Error::Zip { .. } => 5,
+            Error::DiskFull { .. } => 6,
+            Error::Ftp { .. } | Error::Sftp { .. } => 7,
+            Error::Auth { .. } => 8,
+            Error::Mismatch(_) => 9,
+        }
+    }
+}
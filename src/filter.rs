@@ -0,0 +1,86 @@
+use glob::{MatchOptions, Pattern};
+
+use crate::{entry::DirEntry, Error};
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Glob patterns selecting entries by their path inside the image, e.g. `media/**`.
+/// Patterns without a slash match the entry name in any directory, e.g. `*.xmv`.
+/// Matching is case-insensitive.
+#[derive(Debug, Default, Clone)]
+pub struct PathFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, Error> {
+        Ok(PathFilter {
+            include: parse_patterns(include)?,
+            exclude: parse_patterns(exclude)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Returns the entries selected by the filter. Directories are kept when they
+    /// match an include pattern themselves or contain at least one selected entry.
+    pub(crate) fn apply(&self, entries: &[DirEntry]) -> Vec<DirEntry> {
+        self.apply_dir(entries, "", self.include.is_empty())
+    }
+
+    fn apply_dir(&self, entries: &[DirEntry], parent: &str, included: bool) -> Vec<DirEntry> {
+        let mut selected = Vec::new();
+        for entry in entries.iter() {
+            let path = if parent.is_empty() {
+                entry.name.clone()
+            } else {
+                format!("{}/{}", parent, &entry.name)
+            };
+            if matches_any(&self.exclude, &path, &entry.name) {
+                continue;
+            }
+
+            let included = included || matches_any(&self.include, &path, &entry.name);
+            match &entry.subdir {
+                Some(subdir) => {
+                    let children = self.apply_dir(subdir, &path, included);
+                    if included || !children.is_empty() {
+                        let mut entry = entry.clone();
+                        entry.subdir = Some(children);
+                        selected.push(entry);
+                    }
+                }
+                None if included => selected.push(entry.clone()),
+                None => {}
+            }
+        }
+        selected
+    }
+}
+
+fn parse_patterns(patterns: &[String]) -> Result<Vec<Pattern>, Error> {
+    patterns
+        .iter()
+        .map(|p| {
+            Pattern::new(p.trim_start_matches('/'))
+                .map_err(|e| Error::Invalid(format!("Invalid pattern {:?}: {}", p, e)))
+        })
+        .collect()
+}
+
+fn matches_any(patterns: &[Pattern], path: &str, name: &str) -> bool {
+    patterns.iter().any(|pattern| {
+        if pattern.as_str().contains('/') {
+            pattern.matches_with(path, MATCH_OPTIONS)
+        } else {
+            pattern.matches_with(name, MATCH_OPTIONS)
+        }
+    })
+}
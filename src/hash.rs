@@ -0,0 +1,247 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use md5::{Digest, Md5};
+use pbr::{ProgressBar, Units};
+use sha1::Sha1;
+
+use crate::{entry::DirEntry, sink, Error, XIso, IMAGE_BUFFER_SIZE};
+
+/// Size, CRC32, MD5 and SHA-1 of a file or an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hashes {
+    pub size: u64,
+    pub crc32: u32,
+    pub md5: [u8; 16],
+    pub sha1: [u8; 20],
+}
+
+impl Hashes {
+    pub fn crc32_hex(&self) -> String {
+        format!("{:08x}", self.crc32)
+    }
+
+    pub fn md5_hex(&self) -> String {
+        to_hex(&self.md5)
+    }
+
+    pub fn sha1_hex(&self) -> String {
+        to_hex(&self.sha1)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+/// Computes all hashes in a single pass over the data
+#[derive(Default)]
+pub(crate) struct Hasher {
+    size: u64,
+    crc32: crc32fast::Hasher,
+    md5: Md5,
+    sha1: Sha1,
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        self.size += data.len() as u64;
+        self.crc32.update(data);
+        self.md5.update(data);
+        self.sha1.update(data);
+    }
+
+    pub fn finish(self) -> Hashes {
+        Hashes {
+            size: self.size,
+            crc32: self.crc32.finalize(),
+            md5: self.md5.finalize().into(),
+            sha1: self.sha1.finalize().into(),
+        }
+    }
+}
+
+/// Hashes of every file of the image, with paths relative to the image root, and of
+/// the image itself
+#[derive(Debug)]
+pub struct HashManifest {
+    pub files: Vec<(String, Hashes)>,
+    /// Hashes of the complete image file
+    pub image: Hashes,
+    /// Hashes of the game partition, from the partition offset to the end of the image
+    pub partition: Hashes,
+}
+
+impl HashManifest {
+    /// Writes `<base>.sfv`, `<base>.md5` and `<base>.sha1` in the formats of cksfv and
+    /// md5sum/sha1sum. The checks are run from the directory the image was extracted to.
+    /// The image hashes are only part of the sfv file, as comments.
+    /// Returns the paths of the written files.
+    pub fn write(&self, base: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut sfv = String::new();
+        let _ = writeln!(sfv, "; Generated by xiso-ex");
+        for (name, hashes) in [("image", &self.image), ("partition", &self.partition)] {
+            let _ = writeln!(
+                sfv,
+                "; {} {} CRC32 {} MD5 {} SHA-1 {}",
+                name,
+                hashes.size,
+                hashes.crc32_hex(),
+                hashes.md5_hex(),
+                hashes.sha1_hex()
+            );
+        }
+        let mut md5 = String::new();
+        let mut sha1 = String::new();
+        for (path, hashes) in self.files.iter() {
+            let _ = writeln!(sfv, "{} {}", path, hashes.crc32_hex().to_uppercase());
+            let _ = writeln!(md5, "{}  {}", hashes.md5_hex(), path);
+            let _ = writeln!(sha1, "{}  {}", hashes.sha1_hex(), path);
+        }
+
+        let mut written = Vec::new();
+        for (extension, content) in [("sfv", sfv), ("md5", md5), ("sha1", sha1)] {
+            let path = PathBuf::from(format!("{}.{}", base.to_string_lossy(), extension));
+            std::fs::File::create(&path)
+                .and_then(|mut file| file.write_all(content.as_bytes()))
+                .map_err(|e| Error::io(format!("Error writing {:?}", &path), e))?;
+            written.push(path);
+        }
+        Ok(written)
+    }
+}
+
+impl<R: Read + Seek> XIso<R> {
+    /// Hashes the content of every selected file and the image itself, see
+    /// [`XIso::set_filter`]
+    pub fn hash(&mut self, skip_update: bool) -> Result<HashManifest, Error> {
+        let entries = self.selected_entries(skip_update);
+        let (_, _, data_size) = crate::count_entries(&entries);
+
+        let mut pb = ProgressBar::new(data_size);
+        pb.set_units(Units::Bytes);
+        pb.show_speed = false;
+        pb.show_time_left = false;
+
+        let mut files = Vec::new();
+        self.hash_dir(&entries, "", &mut files, &mut pb)?;
+        pb.finish_print("");
+        println!();
+
+        let (image, partition) = self.hash_image()?;
+        for (name, hashes) in [("Image:    ", &image), ("Partition:", &partition)] {
+            println!(
+                "{} {} bytes, CRC32 {}, MD5 {}, SHA-1 {}",
+                name,
+                hashes.size,
+                hashes.crc32_hex(),
+                hashes.md5_hex(),
+                hashes.sha1_hex()
+            );
+        }
+        println!("Files hashed: {}", files.len());
+
+        Ok(HashManifest {
+            files,
+            image,
+            partition,
+        })
+    }
+
+    /// Returns the hashes of the complete image and of its game partition
+    pub fn hash_image(&mut self) -> Result<(Hashes, Hashes), Error> {
+        let image_size = self
+            .reader
+            .seek(SeekFrom::End(0))
+            .and_then(|size| self.reader.seek(SeekFrom::Start(0)).map(|_| size))
+            .map_err(|e| Error::image("Error reading from ISO file", e))?;
+        let partition_offset = self.meta.root_offset;
+
+        let mut pb = ProgressBar::new(image_size);
+        pb.set_units(Units::Bytes);
+        pb.message("Image: ");
+
+        let mut image = Hasher::default();
+        let mut partition = Hasher::default();
+        let mut buffer = vec![0; IMAGE_BUFFER_SIZE];
+        let mut position = 0_u64;
+        loop {
+            let count = match self.reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::image("Error reading from ISO file", e)),
+            };
+            image.update(&buffer[..count]);
+            let partition_start = partition_offset.saturating_sub(position).min(count as u64);
+            partition.update(&buffer[partition_start as usize..count]);
+            position += count as u64;
+            pb.add(count as u64);
+        }
+        pb.finish_print("");
+        println!();
+
+        Ok((image.finish(), partition.finish()))
+    }
+
+    fn hash_dir(
+        &mut self,
+        entries: &[DirEntry],
+        parent: &str,
+        files: &mut Vec<(String, Hashes)>,
+        pb: &mut ProgressBar<io::Stdout>,
+    ) -> Result<(), Error> {
+        for entry in entries.iter() {
+            let path = sink::join_path(parent, &entry.name);
+            if let Some(subdir) = &entry.subdir {
+                self.hash_dir(subdir, &path, files, pb)?;
+            } else if !entry.is_dir() {
+                pb.message(format!("{}: ", &entry.name).as_str());
+                let hashes = self.hash_file(entry, pb)?;
+                files.push((path, hashes));
+            }
+        }
+        Ok(())
+    }
+
+    fn hash_file(
+        &mut self,
+        entry: &DirEntry,
+        pb: &mut ProgressBar<io::Stdout>,
+    ) -> Result<Hashes, Error> {
+        let mut reader = self.open(entry)?;
+        let mut hasher = Hasher::default();
+        let mut buffer = vec![0; crate::BUFFER_SIZE as usize];
+        loop {
+            let count = reader
+                .read(&mut buffer)
+                .map_err(|e| Error::image("Error reading from ISO file", e))?;
+            if count == 0 {
+                break;
+            }
+            hasher.update(&buffer[..count]);
+            pb.add(count as u64);
+        }
+
+        let hashes = hasher.finish();
+        if hashes.size != entry.size as u64 {
+            return Err(Error::image(
+                "Error reading from ISO file",
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "{:?} ends after {} of {} bytes",
+                        &entry.name, hashes.size, entry.size
+                    ),
+                ),
+            ));
+        }
+        Ok(hashes)
+    }
+}
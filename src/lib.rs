@@ -1,290 +1,590 @@
+mod archive;
+mod check;
+mod credentials;
+mod dat;
 mod entry;
+mod entry_reader;
+mod error;
+mod filter;
 mod ftp;
+mod hash;
+mod listing;
 mod meta;
+#[cfg(all(unix, feature = "fuse"))]
+mod mount;
+mod parallel;
+mod salvage;
+mod sftp;
+mod sink;
+mod verify;
+mod writer;
 
 use std::{
     cmp::min,
     io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
-    path::PathBuf,
-    str::FromStr,
+    path::{Path, PathBuf},
 };
 
-use entry::DirEntry;
-use ftp::FtpClient;
-use meta::{get_iso_meta, IsoMeta, SECTOR_SIZE};
+use meta::{get_iso_meta, SECTOR_SIZE};
+
+pub use archive::{TarSink, ZipSink};
+pub use check::{CheckReport, Finding, Severity};
+pub use dat::{Dat, DatGame, DatMatch, DatRom, DumpKind, Validation};
+pub use entry::{DirEntry, ParseStrategy};
+pub use entry_reader::EntryReader;
+pub use error::Error;
+pub use filter::PathFilter;
+pub use ftp::{FtpClient, FtpClientBuilder, FtpMode};
+pub use hash::{HashManifest, Hashes};
+pub use listing::{ListEntry, ListFormat};
+pub use meta::{DiscLayout, IsoMeta};
 use pbr::{ProgressBar, Units};
+pub use salvage::{Damage, DamageReport};
+pub use sftp::SftpSink;
+pub use sink::{FtpSink, LocalSink, OutputSink};
+pub use verify::VerifyReport;
 
 const BUFFER_SIZE: u32 = 4096;
+/// Chunk size of reads over large parts of the image, which has several gigabytes
+const IMAGE_BUFFER_SIZE: usize = 1024 * 1024;
 
-#[derive(Copy, Clone, PartialEq)]
-pub enum FsMode {
-    Local,
-    FTP,
-}
-
-pub struct XIso {
-    file_path: PathBuf,
-    reader: BufReader<std::fs::File>,
+pub struct XIso<R: Read + Seek = BufReader<std::fs::File>> {
+    file_path: Option<PathBuf>,
+    reader: R,
     meta: IsoMeta,
-    fs_mode: FsMode,
+    strategy: ParseStrategy,
+    /// Directory tables skipped as broken, only when opened for salvaging
+    damage: Vec<Damage>,
     pub root: Vec<DirEntry>,
-    ftp_client: Option<FtpClient>,
+    filter: PathFilter,
+    jobs: usize,
+    ftp_options: FtpClientBuilder,
 }
 
-impl XIso {
-    pub fn from_path(path: &PathBuf) -> Result<Self, String> {
+impl XIso<BufReader<std::fs::File>> {
+    pub fn from_path(path: &PathBuf) -> Result<Self, Error> {
+        XIso::from_path_with(path, ParseStrategy::default())
+    }
+
+    /// Opens the image reading its directory tables with the given strategy
+    pub fn from_path_with(path: &PathBuf, strategy: ParseStrategy) -> Result<Self, Error> {
+        let file =
+            std::fs::File::open(path).map_err(|e| Error::io("Error opening input file", e))?;
+
+        let mut xiso = XIso::from_reader_with(BufReader::new(file), strategy)?;
+        xiso.file_path = Some(path.clone());
+        Ok(xiso)
+    }
+
+    /// Opens a damaged image. Directory tables which can not be read are recorded
+    /// in [`XIso::damage`] instead of failing, with the entries read up to the
+    /// failure kept.
+    pub fn from_path_salvage(path: &PathBuf, strategy: ParseStrategy) -> Result<Self, Error> {
         let file =
-            std::fs::File::open(&path).map_err(|e| format!("Error opening input file: {}", e))?;
+            std::fs::File::open(path).map_err(|e| Error::io("Error opening input file", e))?;
+
+        let mut xiso = XIso::from_reader_salvage(BufReader::new(file), strategy)?;
+        xiso.file_path = Some(path.clone());
+        Ok(xiso)
+    }
 
-        let mut reader = BufReader::new(file);
+    /// Creates an XDVDFS image from the content of a local directory.
+    /// Returns the number of files written.
+    pub fn create(source_dir: &Path, out_path: &Path) -> Result<u32, Error> {
+        let mut root = writer::read_dir_tree(source_dir)?;
+        let (files_total, data_size) = writer::count_nodes(&root);
 
+        let file = std::fs::File::create(out_path)
+            .map_err(|e| Error::io(format!("Error creating image file {:?}", out_path), e))?;
+        let mut image_writer = BufWriter::new(file);
+
+        let mut pb = ProgressBar::new(data_size);
+        pb.set_units(Units::Bytes);
+        pb.show_speed = false;
+        pb.show_time_left = false;
+
+        let mut buffer = vec![0; BUFFER_SIZE as usize];
+        let creation_time = meta::filetime_now();
+        writer::write_image(
+            &mut image_writer,
+            &mut root,
+            creation_time,
+            |node, image| {
+                let mut file = std::fs::File::open(&node.source)
+                    .map_err(|e| Error::io(format!("Error opening file {:?}", &node.source), e))?;
+                pb.message(format!("{}: ", &node.name).as_str());
+
+                let mut remaining = node.size as usize;
+                while remaining > 0 {
+                    let chunk_size = min(remaining, buffer.len());
+                    file.read_exact(&mut buffer[..chunk_size]).map_err(|e| {
+                        Error::io(format!("Error reading file {:?}", &node.source), e)
+                    })?;
+                    image
+                        .write_all(&buffer[..chunk_size])
+                        .map_err(|e| Error::io("Error writing image", e))?;
+                    remaining -= chunk_size;
+                    pb.add(chunk_size as u64);
+                }
+                Ok(())
+            },
+        )?;
+
+        pb.finish_print(&out_path.to_string_lossy());
+        println!();
+        Ok(files_total)
+    }
+}
+
+impl<R: Read + Seek> XIso<R> {
+    /// Reads the image from any seekable source, e.g. an in-memory buffer
+    pub fn from_reader(reader: R) -> Result<Self, Error> {
+        XIso::from_reader_with(reader, ParseStrategy::default())
+    }
+
+    /// Reads the image from any seekable source with the given strategy for the
+    /// directory tables
+    pub fn from_reader_with(reader: R, strategy: ParseStrategy) -> Result<Self, Error> {
+        XIso::read_image(reader, strategy, false)
+    }
+
+    /// Reads a damaged image from any seekable source, see [`XIso::from_path_salvage`]
+    pub fn from_reader_salvage(reader: R, strategy: ParseStrategy) -> Result<Self, Error> {
+        XIso::read_image(reader, strategy, true)
+    }
+
+    fn read_image(mut reader: R, strategy: ParseStrategy, salvage: bool) -> Result<Self, Error> {
         let iso_meta = get_iso_meta(&mut reader)?;
 
-        let root_dir = entry::parse_root(&mut reader, &iso_meta)?;
+        let mut damage = Vec::new();
+        let root_dir = entry::parse_root(
+            &mut reader,
+            &iso_meta,
+            strategy,
+            salvage.then_some(&mut damage),
+        )?;
 
         Ok(XIso {
-            file_path: path.clone(),
+            file_path: None,
             reader,
             meta: iso_meta,
+            strategy,
+            damage,
             root: root_dir,
-            fs_mode: FsMode::Local,
-            ftp_client: None,
+            filter: PathFilter::default(),
+            jobs: 1,
+            ftp_options: FtpClientBuilder::default(),
         })
     }
 
-    pub fn extract_all(&mut self, out_path: &String, skip_update: bool) -> Result<(), String> {
-        let mut path = out_path.clone();
-        if out_path.starts_with("ftp://") {
-            self.fs_mode = FsMode::FTP;
-            let ftp_client = FtpClient::connect(out_path)?;
-            path = ftp_client.get_path();
-            self.ftp_client = Some(ftp_client);          
-        }      
+    /// Disc layout the image was detected as
+    pub fn layout(&self) -> DiscLayout {
+        self.meta.layout
+    }
+
+    /// Volume descriptor of the image
+    pub fn meta(&self) -> &IsoMeta {
+        &self.meta
+    }
 
-        self.create_out_dir(&path)?;  
+    /// Strategy the directory tables were read with
+    pub fn parse_strategy(&self) -> ParseStrategy {
+        self.strategy
+    }
+
+    /// Directory tables found broken when opening the image for salvaging
+    pub fn damage(&self) -> &[Damage] {
+        &self.damage
+    }
 
+    /// Returns a read and seek handle for the content of a file
+    pub fn open(&mut self, entry: &DirEntry) -> Result<EntryReader<'_, R>, Error> {
+        if entry.is_dir() {
+            return Err(Error::Invalid(format!("{:?} is a directory", &entry.name)));
+        }
+        let position = self.meta.root_offset + entry.sector as u64 * SECTOR_SIZE as u64;
+        EntryReader::new(&mut self.reader, position, entry.size as u64).map_err(|e| {
+            Error::image(
+                format!("Unable to jump to record at {}. Broken ISO?", position),
+                e,
+            )
+        })
+    }
+
+    /// Returns a read and seek handle for the file at the given path inside the image,
+    /// e.g. `/default.xex`
+    pub fn open_path(&mut self, path: &str) -> Result<EntryReader<'_, R>, Error> {
+        let entry = entry::find_entry(&self.root, path)
+            .ok_or(Error::Invalid(format!(
+                "Path {:?} not found in ISO file",
+                path
+            )))?
+            .clone();
+        self.open(&entry)
+    }
+
+    /// Restricts extraction to the entries selected by the filter
+    pub fn set_filter(&mut self, filter: PathFilter) {
+        self.filter = filter;
+    }
+
+    /// Number of parallel connections used when extracting to a server.
+    /// Each connection reads from its own handle of the image file.
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.jobs = jobs.max(1);
+    }
+
+    /// Connection settings used when extracting to an FTP server
+    pub fn set_ftp_options(&mut self, options: FtpClientBuilder) {
+        self.ftp_options = options;
+    }
+
+    pub fn extract_all(&mut self, out_path: &str, skip_update: bool) -> Result<(), Error> {
+        if self.jobs > 1 && sink::is_remote(out_path) {
+            let entries = self.selected_entries(skip_update);
+            return self.extract_parallel(out_path, &entries);
+        }
+        let mut sink = sink::open(out_path, &self.ftp_options, false)?;
+        self.extract_all_to(sink.as_mut(), skip_update)
+    }
+
+    /// Extracts the content of the image into a custom output sink
+    pub fn extract_all_to(
+        &mut self,
+        sink: &mut dyn OutputSink,
+        skip_update: bool,
+    ) -> Result<(), Error> {
+        let entries = self.selected_entries(skip_update);
+        self.extract_entries(sink, &entries)
+    }
+
+    fn selected_entries(&self, skip_update: bool) -> Vec<DirEntry> {
         let mut entries = self.root.clone();
         if skip_update {
-            entries = entries
-                .into_iter()
-                .filter(|e| e.name != "$SystemUpdate")
-                .collect();
+            entries.retain(|e| e.name != "$SystemUpdate");
         }
-        let files_count = self.extract_records(&entries, &path)?;
-        println!("");
+        if !self.filter.is_empty() {
+            entries = self.filter.apply(&entries);
+        }
+        entries
+    }
+
+    /// Extracts a single file or directory, given by its path inside the image,
+    /// into the output directory. Other content of the directory is kept.
+    pub fn extract_path(&mut self, path: &str, out_path: &str) -> Result<(), Error> {
+        let entry = entry::find_entry(&self.root, path)
+            .ok_or(Error::Invalid(format!(
+                "Path {:?} not found in ISO file",
+                path
+            )))?
+            .clone();
+        let mut sink = sink::open(out_path, &self.ftp_options, true)?;
+        self.extract_entries(sink.as_mut(), &[entry])
+    }
+
+    fn extract_entries(
+        &mut self,
+        sink: &mut dyn OutputSink,
+        entries: &[DirEntry],
+    ) -> Result<(), Error> {
+        sink.prepare()?;
+
+        let files_count = self.extract_records(sink, entries, "")?;
+        sink.finish()?;
+        println!();
         println!("Files extracted: {}", files_count);
 
         Ok(())
     }
 
-    pub fn list(self) {
-        println!("Printing content of {:?}", &self.file_path);
-        let path = PathBuf::from("/");
-        let files_total = print_dir(&self.root, &path);
+    fn extract_parallel(&self, out_path: &str, entries: &[DirEntry]) -> Result<(), Error> {
+        let image_path = self.file_path.as_ref().ok_or(Error::Invalid(
+            "Parallel extraction requires an image opened from a file".to_string(),
+        ))?;
+        let files_count = parallel::extract(
+            image_path,
+            self.meta.root_offset,
+            out_path,
+            &self.ftp_options,
+            entries,
+            self.jobs,
+        )?;
         println!();
-        println!("Number of files: {}", files_total);
+        println!("Files extracted: {}", files_count);
+
+        Ok(())
     }
 
-    fn create_out_dir(&mut self, out_path: &String) -> Result<(), String> {
-        if self.fs_mode == FsMode::Local {
-            let out_path = PathBuf::from_str(out_path)
-                .map_err(|e| format!("Error parsing output path '{}': {}", out_path, e))?;
-            if out_path.exists() {
-                print!("Output dir {:?} already exists. Replacing.", &out_path);
-                std::fs::remove_dir_all(&out_path).map_err(|e| {
-                    format!("Error deleting output directory {:?}: {}", &out_path, e)
-                })?;
+    /// Writes a compact image containing only the game partition. The file data is
+    /// repacked into contiguous sectors, dropping the video partition and padding.
+    /// Returns the number of files written. Refuses to overwrite the image itself.
+    pub fn rewrite(&mut self, out_path: &Path, skip_update: bool) -> Result<u32, Error> {
+        // creating the output truncates it before anything is read
+        if let Some(in_path) = &self.file_path {
+            let same_file = match (in_path.canonicalize(), out_path.canonicalize()) {
+                (Ok(in_path), Ok(out_path)) => in_path == out_path,
+                _ => false,
+            };
+            if same_file {
+                return Err(Error::Invalid(format!(
+                    "Refusing to overwrite the input image {:?}",
+                    in_path
+                )));
             }
-
-            std::fs::create_dir_all(&out_path)
-                .map_err(|e| format!("Error creating output directory {:?}: {}", &out_path, e))?;
-        } else if self.fs_mode == FsMode::FTP {
-            let ftp = self.ftp_client.as_mut().unwrap();
-            ftp.create_dir_all(out_path)?;
         }
+        let mut entries = self.root.clone();
+        if skip_update {
+            entries.retain(|e| e.name != "$SystemUpdate");
+        }
+        let mut root = to_nodes(&entries);
+        let (files_total, data_size) = writer::count_nodes(&root);
 
-        Ok(())
+        let file = std::fs::File::create(out_path)
+            .map_err(|e| Error::io(format!("Error creating image file {:?}", out_path), e))?;
+        let mut image_writer = BufWriter::new(file);
+
+        let mut pb = ProgressBar::new(data_size);
+        pb.set_units(Units::Bytes);
+        pb.show_speed = false;
+        pb.show_time_left = false;
+
+        let mut buffer = vec![0; BUFFER_SIZE as usize];
+        let creation_time = self.meta.creation_time;
+        writer::write_image(
+            &mut image_writer,
+            &mut root,
+            creation_time,
+            |node, image| {
+                let position = self.meta.root_offset + node.source as u64 * SECTOR_SIZE as u64;
+                self.reader.seek(SeekFrom::Start(position)).map_err(|e| {
+                    Error::image(
+                        format!("Unable to jump to record at {}. Broken ISO?", position),
+                        e,
+                    )
+                })?;
+                pb.message(format!("{}: ", &node.name).as_str());
+
+                let mut remaining = node.size as usize;
+                while remaining > 0 {
+                    let chunk_size = min(remaining, buffer.len());
+                    self.reader
+                        .read_exact(&mut buffer[..chunk_size])
+                        .map_err(|e| Error::image("Error reading from ISO file", e))?;
+                    image
+                        .write_all(&buffer[..chunk_size])
+                        .map_err(|e| Error::io("Error writing image", e))?;
+                    remaining -= chunk_size;
+                    pb.add(chunk_size as u64);
+                }
+                Ok(())
+            },
+        )?;
+
+        pb.finish_print(&out_path.to_string_lossy());
+        println!();
+        Ok(files_total)
     }
 
-    fn dir_exists(&mut self, dir_path: &String) -> Result<bool, String> {
-        if self.fs_mode == FsMode::Local {
-            let dir_path = PathBuf::from_str(dir_path)
-                .map_err(|e| format!("Error parsing output path '{}': {}", dir_path, e))?;
-            return Ok(dir_path.exists());
-        } else if self.fs_mode == FsMode::FTP {
-            let ftp = self.ftp_client.as_mut().unwrap();
-            return ftp.exists(dir_path);
+    /// Mounts the image as read-only FUSE filesystem. Blocks until it gets unmounted.
+    #[cfg(all(unix, feature = "fuse"))]
+    pub fn mount(self, mount_point: &Path) -> Result<(), Error> {
+        if !mount_point.exists() {
+            std::fs::create_dir_all(mount_point).map_err(|e| {
+                Error::io(format!("Error creating mount point {:?}", mount_point), e)
+            })?;
         }
 
-        Err(format!("Unsupported mode"))
+        let fs = mount::XIsoFs::new(
+            self.reader,
+            self.meta.root_offset,
+            self.meta.creation_system_time(),
+            &self.root,
+        );
+        let options = [
+            fuser::MountOption::RO,
+            fuser::MountOption::FSName("xiso-ex".to_string()),
+            fuser::MountOption::DefaultPermissions,
+        ];
+        fuser::mount2(fs, mount_point, &options)
+            .map_err(|e| Error::io(format!("Error mounting {:?}", mount_point), e))
     }
 
-    fn create_dir(&mut self, dir_path: &String) -> Result<(), String> {
-        if self.fs_mode == FsMode::Local {
-            return std::fs::create_dir(dir_path)
-                .map_err(|e| format!("Error creating output directory {:?}: {}", dir_path, e));
-        } else if self.fs_mode == FsMode::FTP {
-            let ftp = self.ftp_client.as_mut().unwrap();
-            ftp.mkdir(dir_path)?
+    pub fn list(self) {
+        if let Some(file_path) = &self.file_path {
+            println!("Printing content of {:?}", file_path);
         }
+        let path = PathBuf::from("/");
+        let files_total = print_dir(&self.root, &path);
+        println!();
+        println!("Number of files: {}", files_total);
+    }
 
-        Ok(())
+    pub fn info(&self) {
+        let (files_total, dirs_total, data_size) = count_entries(&self.root);
+        let root_position =
+            self.meta.root_offset + self.meta.root_dir_sector as u64 * SECTOR_SIZE as u64;
+
+        if let Some(file_path) = &self.file_path {
+            println!("Image:              {:?}", file_path);
+        }
+        println!("Layout:             {}", self.meta.layout);
+        println!("Partition offset:   {:#x}", self.meta.root_offset);
+        println!(
+            "Root directory:     sector {}, {} bytes at {:#x}",
+            self.meta.root_dir_sector, self.meta.root_dir_size, root_position
+        );
+        println!("Creation time:      {}", self.meta.creation_date());
+        println!("Directories:        {}", dirs_total);
+        println!("Files:              {}", files_total);
+        println!("Total data size:    {} bytes", data_size);
     }
 
     fn extract_records(
         &mut self,
-        entries: &Vec<DirEntry>,
-        root_path: &String,
-    ) -> Result<u32, String> {
+        sink: &mut dyn OutputSink,
+        entries: &[DirEntry],
+        parent: &str,
+    ) -> Result<u32, Error> {
         let mut count = 0_u32;
         for entry in entries.iter() {
             if entry.is_dir() {
-                let new_dir = format!("{}/{}", root_path, &entry.name);
-                if !self.dir_exists(&new_dir)? {
-                    self.create_dir(&new_dir)?
-                }
+                let new_dir = sink::join_path(parent, &entry.name);
+                with_reconnect(sink, |sink| {
+                    if !sink.exists(&new_dir)? {
+                        sink.create_dir(&new_dir)?
+                    }
+                    Ok(())
+                })?;
                 if let Some(entries) = &entry.subdir {
-                    count += self.extract_records(entries, &new_dir)?;
+                    count += self.extract_records(sink, entries, &new_dir)?;
                 };
             } else {
-                self.extract_record(entry, &root_path)?;
+                with_reconnect(sink, |sink| self.extract_record(sink, entry, parent))?;
                 count += 1;
             }
         }
         Ok(count)
     }
 
-    fn extract_record(&mut self, entry: &DirEntry, output_root: &String) -> Result<(), String> {
-        let position = self.meta.root_offset + entry.sector as u64 * SECTOR_SIZE as u64;
-        self.reader
-            .seek(SeekFrom::Start(position))
-            .map_err(|_| format!("Unable to jump to record at {}. Broken ISO?", position))?;
-
-        let out_file = format!("{}/{}", output_root, &entry.name);
-        let mut file_writer = None;
-        let mut ftp_writer = None;
-
-        if self.fs_mode == FsMode::Local {
-            let file = std::fs::File::create(&out_file)
-                .map_err(|e| format!("Error creating file {:?}: {}", &out_file, e))?;
-            file_writer = Some(BufWriter::new(file));
-        } else if self.fs_mode == FsMode::FTP {
-            let ftp = self.ftp_client.as_mut().unwrap();
-
-            let file_size = ftp.get_file_size(&out_file)?;
-            if file_size == -1 {
-                ftp_writer = Some(ftp.put(&out_file)?);
-            } else if file_size != entry.size as i64 {
-                println!("Corrupt remote file: {}, Replacing.", &out_file); // TODO resuming?
-                ftp_writer = Some(ftp.put(&out_file)?);
-            } else {
-                return Ok(());
-            }
-        }
-
-        let buffer_size = min(entry.size, BUFFER_SIZE);
-        let mut buffer = vec![0; buffer_size as usize];
-        let chunk_count = if buffer_size == 0 {
-            0
-        } else {
-            entry.size / buffer_size
+    fn extract_record(
+        &mut self,
+        sink: &mut dyn OutputSink,
+        entry: &DirEntry,
+        parent: &str,
+    ) -> Result<(), Error> {
+        let out_file = sink::join_path(parent, &entry.name);
+        let Some(offset) = resume_offset(sink, entry, &out_file)? else {
+            return Ok(());
         };
 
-        let mut pb = ProgressBar::new(entry.size.into());
+        let mut pb = ProgressBar::new(entry.size as u64);
         pb.set_units(Units::Bytes);
         pb.message(format!("{}: ", &entry.name).as_str());
         pb.show_speed = false;
         pb.show_time_left = false;
+        pb.set(offset);
 
-        for _ in 0..chunk_count {
-            self.reader
-                .read_exact(&mut buffer)
-                .map_err(|e| format!("Error reading from ISO file: {}", e))?;
-
-            if self.fs_mode == FsMode::Local {
-                file_writer
-                    .as_mut()
-                    .unwrap()
-                    .write_all(&buffer[0..buffer_size as usize])
-                    .map_err(|e| format!("Error writing to file {:?}: {}", &out_file, e))?;
-            } else if self.fs_mode == FsMode::FTP {
-                ftp_writer
-                    .as_mut()
-                    .unwrap()
-                    .write_all(&buffer[0..buffer_size as usize])
-                    .map_err(|e| format!("Error writing to ftp file {:?}: {}", &out_file, e))?;
-            }
-            pb.add(buffer_size as u64);
-        }
+        let mut reader = self.open(entry)?;
+        copy_entry(&mut reader, sink, &out_file, offset, &mut |written| {
+            pb.add(written);
+        })?;
 
-        if chunk_count > 0 && entry.size % buffer_size != 0 {
-            let last_chunk_size = (entry.size - buffer_size * chunk_count) as usize;
-            let mut buffer = vec![0; last_chunk_size];
-            self.reader
-                .read_exact(&mut buffer)
-                .map_err(|e| format!("Error reading from ISO file: {}", e))?;
-
-            if self.fs_mode == FsMode::Local {
-                file_writer
-                    .as_mut()
-                    .unwrap()
-                    .write_all(&buffer[0..last_chunk_size])
-                    .map_err(|e| format!("Error writing to file {:?}: {}", &out_file, e))?;
-            } else if self.fs_mode == FsMode::FTP {
-                ftp_writer
-                    .as_mut()
-                    .unwrap()
-                    .write_all(&buffer[0..last_chunk_size])
-                    .map_err(|e| format!("Error writing to ftp file {:?}: {}", &out_file, e))?;
-            }
-            pb.add(last_chunk_size as u64);
+        pb.finish_print(&sink.display_path(&out_file));
+        println!();
+        Ok(())
+    }
+}
+
+/// Runs an operation on the sink, running it again as long as the sink manages to
+/// reestablish its connection after it broke
+pub(crate) fn with_reconnect<T>(
+    sink: &mut dyn OutputSink,
+    mut operation: impl FnMut(&mut dyn OutputSink) -> Result<T, Error>,
+) -> Result<T, Error> {
+    loop {
+        match operation(sink) {
+            Err(e) if sink.reconnect(&e)? => continue,
+            result => return result,
         }
+    }
+}
 
-        if self.fs_mode == FsMode::FTP {
-            let ftp = self.ftp_client.as_mut().unwrap();
-            ftp.put_close(ftp_writer.unwrap())?;
-
-            let out_file = &out_file;
-            let file_size = ftp.get_file_size(&out_file)?;
-            if file_size != entry.size as i64 {
-                return Err(format!(
-                    "File verification failed. {:?} is corrupted.",
-                    &out_file
-                ));
-            }
-        } else if self.fs_mode == FsMode::Local {
-            file_writer
-                .unwrap()
-                .flush()
-                .map_err(|e| format!("Error flushing file writer: {}", e.to_string()))?;
-            let metadata = std::fs::metadata(&out_file).map_err(|e| {
-                format!(
-                    "Error getting metadata for {:?}: {}",
-                    &out_file,
-                    e.to_string()
-                )
-            })?;
-            if metadata.len() != entry.size as u64 {
-                return Err(format!(
-                    "File verification failed. {:?} is corrupted.",
-                    &out_file
-                ));
-            }
+/// Returns the offset to continue writing an output file at, or `None` if the
+/// file is already complete. Files of sinks which can not resume are always
+/// written anew, an existing file of the same size may still differ.
+pub(crate) fn resume_offset(
+    sink: &mut dyn OutputSink,
+    entry: &DirEntry,
+    out_file: &str,
+) -> Result<Option<u64>, Error> {
+    if !sink.supports_resume() {
+        return Ok(Some(0));
+    }
+    let entry_size = entry.size as u64;
+    match sink.file_size(out_file)? {
+        Some(file_size) if file_size == entry_size => Ok(None),
+        Some(file_size) if file_size < entry_size => {
+            println!(
+                "Incomplete file: {}, Resuming at {} bytes.",
+                sink.display_path(out_file),
+                file_size
+            );
+            Ok(Some(file_size))
         }
+        Some(_) => {
+            println!("Corrupt file: {}, Replacing.", sink.display_path(out_file));
+            Ok(Some(0))
+        }
+        None => Ok(Some(0)),
+    }
+}
 
-        pb.finish_print(format!("{}", &out_file).as_str());
-        println!();
-        Ok(())
+/// Copies the file data from `offset` on into the sink and completes the output file.
+/// `progress` is called with the number of bytes of every written chunk.
+pub(crate) fn copy_entry<R: Read + Seek>(
+    reader: &mut EntryReader<'_, R>,
+    sink: &mut dyn OutputSink,
+    out_file: &str,
+    offset: u64,
+    progress: &mut dyn FnMut(u64),
+) -> Result<(), Error> {
+    let entry_size = reader.len();
+    reader
+        .seek(SeekFrom::Start(offset))
+        .map_err(|e| Error::image("Error reading from ISO file", e))?;
+    let mut writer = if offset > 0 {
+        sink.append_file(out_file, offset)?
+    } else {
+        sink.create_file(out_file, entry_size)?
+    };
+
+    let mut buffer = vec![0; min(entry_size, BUFFER_SIZE as u64) as usize];
+    let mut remaining = (entry_size - offset) as usize;
+    while remaining > 0 {
+        let chunk_size = min(remaining, buffer.len());
+        reader
+            .read_exact(&mut buffer[..chunk_size])
+            .map_err(|e| Error::image("Error reading from ISO file", e))?;
+        writer
+            .write_all(&buffer[..chunk_size])
+            .map_err(|e| Error::io(format!("Error writing to file {:?}", out_file), e))?;
+        remaining -= chunk_size;
+        progress(chunk_size as u64);
     }
+
+    drop(writer);
+    sink.finalize(out_file, entry_size)
 }
 
-fn print_dir(entries: &Vec<DirEntry>, cur_dir: &PathBuf) -> u32 {
+fn print_dir(entries: &[DirEntry], cur_dir: &Path) -> u32 {
     let mut count = 0_u32;
     for entry in entries.iter() {
         if entry.is_dir() {
             let cur_dir = cur_dir.join(&entry.name);
-            if let Some(entries) = &entry.subdir {
-                count += print_dir(&entries, &cur_dir);
+            match &entry.subdir {
+                Some(entries) if !entries.is_empty() => count += print_dir(entries, &cur_dir),
+                // empty directories would not show up otherwise
+                _ => println!("{}/", cur_dir.to_str().unwrap()),
             };
         } else {
             println!("{}", cur_dir.join(&entry.name).to_str().unwrap());
@@ -293,3 +593,118 @@ fn print_dir(entries: &Vec<DirEntry>, cur_dir: &PathBuf) -> u32 {
     }
     count
 }
+
+/// Converts parsed entries into writer nodes referencing the original data sectors
+fn to_nodes(entries: &[DirEntry]) -> Vec<writer::Node<u32>> {
+    entries
+        .iter()
+        .map(|entry| {
+            let mut node = match &entry.subdir {
+                Some(subdir) => {
+                    writer::Node::dir(entry.name.clone(), to_nodes(subdir), entry.sector)
+                }
+                None => writer::Node::file(entry.name.clone(), entry.size, entry.sector),
+            };
+            node.attributes = entry.attributes;
+            node
+        })
+        .collect()
+}
+
+/// Returns number of files, number of directories and total file size
+fn count_entries(entries: &[DirEntry]) -> (u32, u32, u64) {
+    let mut totals = (0_u32, 0_u32, 0_u64);
+    for entry in entries.iter() {
+        if entry.is_dir() {
+            totals.1 += 1;
+            if let Some(entries) = &entry.subdir {
+                let (files, dirs, size) = count_entries(entries);
+                totals.0 += files;
+                totals.1 += dirs;
+                totals.2 += size;
+            };
+        } else {
+            totals.0 += 1;
+            totals.2 += entry.size as u64;
+        }
+    }
+    totals
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use super::*;
+    use crate::writer::{self, Node};
+
+    fn image() -> Vec<u8> {
+        let mut root = vec![
+            Node::file("default.xex".to_string(), 5000, b'x'),
+            Node::dir(
+                "media".to_string(),
+                vec![Node::file("intro.xmv".to_string(), 3, b'm')],
+                0,
+            ),
+        ];
+        let mut image = Vec::new();
+        writer::write_image(&mut image, &mut root, 0, |node, image| {
+            image
+                .write_all(&vec![node.source; node.size as usize])
+                .map_err(|e| Error::io("Error writing image", e))
+        })
+        .unwrap();
+        image
+    }
+
+    #[test]
+    fn read_image_from_memory() {
+        let mut xiso = XIso::from_reader(Cursor::new(image())).unwrap();
+        assert_eq!(xiso.layout(), DiscLayout::XIso);
+
+        let mut names: Vec<&str> = xiso.root.iter().map(|e| e.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["default.xex", "media"]);
+
+        let mut content = String::new();
+        xiso.open_path("/media/intro.xmv")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "mmm");
+
+        let default_xex = entry::find_entry(&xiso.root, "/default.xex")
+            .unwrap()
+            .clone();
+        let mut reader = xiso.open(&default_xex).unwrap();
+        assert_eq!(reader.len(), 5000);
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        assert!(content.len() == 5000 && content.iter().all(|&b| b == b'x'));
+
+        assert!(matches!(xiso.open_path("/missing"), Err(Error::Invalid(_))));
+        let media = entry::find_entry(&xiso.root, "/media").unwrap().clone();
+        assert!(matches!(xiso.open(&media), Err(Error::Invalid(_))));
+    }
+
+    #[test]
+    fn rewrite_refuses_to_overwrite_input() {
+        let dir = std::env::temp_dir().join(format!("xiso-ex-{}-rewrite", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("game.iso");
+        let image = image();
+        std::fs::write(&path, &image).unwrap();
+
+        let mut xiso = XIso::from_path(&path).unwrap();
+        let same_path = dir.join(".").join("game.iso");
+        assert!(matches!(
+            xiso.rewrite(&same_path, false),
+            Err(Error::Invalid(_))
+        ));
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+
+        let out_path = dir.join("rewritten.iso");
+        assert_eq!(xiso.rewrite(&out_path, false).unwrap(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
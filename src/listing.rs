@@ -0,0 +1,202 @@
+use std::io::{Read, Seek, Write};
+
+use serde::Serialize;
+
+use crate::{count_entries, entry::DirEntry, meta::SECTOR_SIZE, Error, XIso};
+
+/// Output format of [`XIso::write_list`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ListFormat {
+    /// Paths of the files and of empty directories with a trailing slash, one per line
+    #[default]
+    Text,
+    /// Array of all entries
+    Json,
+    /// Header line and one line per entry
+    Csv,
+}
+
+/// Directory or file of the image with its location
+#[derive(Debug, Clone, Serialize)]
+pub struct ListEntry {
+    /// Absolute path inside the image, e.g. `/media/intro.wmv`
+    pub path: String,
+    /// File size, or size of the directory table for directories
+    pub size: u32,
+    /// Start sector relative to the game partition
+    pub sector: u32,
+    /// Byte offset of the data in the image file
+    pub offset: u64,
+    /// Names of the set attribute flags, see [`DirEntry::attribute_names`]
+    pub attributes: Vec<&'static str>,
+    pub is_dir: bool,
+}
+
+impl<R: Read + Seek> XIso<R> {
+    /// All entries of the image, every directory followed by its content
+    pub fn list_entries(&self) -> Vec<ListEntry> {
+        let mut list = Vec::new();
+        collect_entries(&self.root, "", self.meta.root_offset, &mut list);
+        list
+    }
+
+    /// Writes the listing in a format meant for other programs
+    pub fn write_list(&self, format: ListFormat, out: &mut dyn Write) -> Result<(), Error> {
+        let entries = self.list_entries();
+        match format {
+            ListFormat::Text => {
+                for (index, entry) in entries.iter().enumerate() {
+                    let line = if entry.is_dir {
+                        let dir_path = format!("{}/", entry.path);
+                        // empty directories would not show up otherwise
+                        let is_empty = entries
+                            .get(index + 1)
+                            .is_none_or(|next| !next.path.starts_with(&dir_path));
+                        if !is_empty {
+                            continue;
+                        }
+                        dir_path
+                    } else {
+                        entry.path.clone()
+                    };
+                    writeln!(out, "{}", line).map_err(|e| Error::io("Error writing listing", e))?;
+                }
+            }
+            ListFormat::Json => {
+                serde_json::to_writer_pretty(&mut *out, &entries)
+                    .map_err(|e| Error::io("Error writing listing", e.into()))?;
+                writeln!(out).map_err(|e| Error::io("Error writing listing", e))?;
+            }
+            ListFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(out);
+                writer
+                    .write_record(["path", "size", "sector", "offset", "attributes", "is_dir"])
+                    .map_err(|e| Error::io("Error writing listing", e.into()))?;
+                for entry in entries.iter() {
+                    writer
+                        .write_record([
+                            entry.path.clone(),
+                            entry.size.to_string(),
+                            entry.sector.to_string(),
+                            entry.offset.to_string(),
+                            entry.attributes.join(" "),
+                            entry.is_dir.to_string(),
+                        ])
+                        .map_err(|e| Error::io("Error writing listing", e.into()))?;
+                }
+                writer
+                    .flush()
+                    .map_err(|e| Error::io("Error writing listing", e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn collect_entries(
+    entries: &[DirEntry],
+    parent: &str,
+    root_offset: u64,
+    list: &mut Vec<ListEntry>,
+) {
+    for entry in entries.iter() {
+        let path = format!("{}/{}", parent, entry.name);
+        list.push(ListEntry {
+            path: path.clone(),
+            size: entry.size,
+            sector: entry.sector,
+            offset: root_offset + entry.sector as u64 * SECTOR_SIZE as u64,
+            attributes: entry.attribute_names(),
+            is_dir: entry.is_dir(),
+        });
+        if let Some(subdir) = &entry.subdir {
+            collect_entries(subdir, &path, root_offset, list);
+        }
+    }
+}
+
+impl<R: Read + Seek> XIso<R> {
+    /// Prints the directory tree with the number of files and the size of the
+    /// content of every directory
+    pub fn print_tree(&self) {
+        let (files_total, dirs_total, data_size) = count_entries(&self.root);
+        println!("/ ({} files, {})", files_total, format_size(data_size));
+        print_tree_level(&self.root, "");
+        println!();
+        println!(
+            "{} directories, {} files, {}",
+            dirs_total,
+            files_total,
+            format_size(data_size)
+        );
+    }
+
+    /// Prints the size and number of files of every directory including its
+    /// subdirectories, deepest first like `du`
+    pub fn print_du(&self) {
+        let (files_total, _, data_size) = count_entries(&self.root);
+        print_du_level(&self.root, "");
+        print_du_line(data_size, files_total, "/");
+    }
+}
+
+fn print_tree_level(entries: &[DirEntry], prefix: &str) {
+    for (index, entry) in entries.iter().enumerate() {
+        let (branch, indent) = if index + 1 == entries.len() {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        if entry.is_dir() {
+            let subdir = entry.subdir.as_deref().unwrap_or_default();
+            let (files, _, size) = count_entries(subdir);
+            println!(
+                "{}{}{}/ ({} files, {})",
+                prefix,
+                branch,
+                entry.name,
+                files,
+                format_size(size)
+            );
+            print_tree_level(subdir, &format!("{}{}", prefix, indent));
+        } else {
+            println!(
+                "{}{}{} ({})",
+                prefix,
+                branch,
+                entry.name,
+                format_size(entry.size as u64)
+            );
+        }
+    }
+}
+
+fn print_du_level(entries: &[DirEntry], parent: &str) {
+    for entry in entries.iter().filter(|e| e.is_dir()) {
+        let path = format!("{}/{}", parent, entry.name);
+        let subdir = entry.subdir.as_deref().unwrap_or_default();
+        print_du_level(subdir, &path);
+        let (files, _, size) = count_entries(subdir);
+        print_du_line(size, files, &path);
+    }
+}
+
+fn print_du_line(size: u64, files: u32, path: &str) {
+    println!("{:>10}  {:>7} files  {}", format_size(size), files, path);
+}
+
+/// Size with binary units, e.g. `4.77 MB`
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", size)
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
@@ -1,23 +1,163 @@
 mod cli;
 use clap::Parser;
-use xiso_ex::XIso;
+use std::{path::PathBuf, process::ExitCode, time::Duration};
+use xiso_ex::{
+    Dat, Error, FtpClientBuilder, FtpMode, ListFormat, ParseStrategy, PathFilter, Severity, XIso,
+};
 
-fn main() -> Result<(), String> {
+fn main() -> ExitCode {
     let cli = cli::Cli::parse();
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e.report());
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+fn run(cli: cli::Cli) -> Result<(), Error> {
+    let ftp_options = ftp_options(&cli);
 
     //let input_path = std::path::absolute(cli.input).unwrap();
     let input_path = cli.input;
     let mode = &cli.mode;
     let skip_update = cli.skip_update;
 
-    let mut xiso = XIso::from_path(&input_path)?;
+    if mode.create {
+        let output_path = cli
+            .out
+            .map(PathBuf::from)
+            .unwrap_or(input_path.with_extension("iso"));
+        println!("Creating {:?} from {:?}", &output_path, &input_path);
+        let files_count = XIso::create(&input_path, &output_path)?;
+        println!("Files written: {}", files_count);
+        return Ok(());
+    }
+
+    let strategy = match cli.parser {
+        cli::ParserArg::Tree => ParseStrategy::Tree,
+        cli::ParserArg::Scan => ParseStrategy::Scan,
+    };
+    let mut xiso = if mode.salvage || mode.check {
+        XIso::from_path_salvage(&input_path, strategy)?
+    } else {
+        XIso::from_path_with(&input_path, strategy)?
+    };
+
+    // machine-readable listings go to stdout without any other messages
+    if mode.list && cli.format != cli::FormatArg::Text {
+        let format = match cli.format {
+            cli::FormatArg::Text => ListFormat::Text,
+            cli::FormatArg::Json => ListFormat::Json,
+            cli::FormatArg::Csv => ListFormat::Csv,
+        };
+        return xiso.write_list(format, &mut std::io::stdout().lock());
+    }
+
+    println!("Detected {} image", xiso.layout());
 
     if mode.list {
-        xiso.list();
+        if cli.tree {
+            xiso.print_tree();
+        } else if cli.du {
+            xiso.print_du();
+        } else {
+            xiso.list();
+        }
+        return Ok(());
+    }
+
+    if mode.validate {
+        let dat_path = cli
+            .dat
+            .ok_or(Error::Invalid("Missing DAT file".to_string()))?;
+        let dat = Dat::from_path(&dat_path)?;
+        println!("Validating {:?} against {:?}", &input_path, &dat_path);
+        if !xiso.validate(&dat)?.is_known() {
+            return Err(Error::Mismatch("Image not found in DAT file".to_string()));
+        }
+        return Ok(());
+    }
+
+    if mode.check {
+        println!("Checking {:?}", &input_path);
+        let report = xiso.check()?;
+        if !report.is_ok() {
+            return Err(Error::Corrupt(format!(
+                "Check failed, errors found: {}",
+                report.count(Severity::Error)
+            )));
+        }
+        return Ok(());
+    }
+
+    if mode.info {
+        xiso.info();
+        return Ok(());
+    }
+
+    if mode.rewrite {
+        let output_path = cli
+            .out
+            .map(PathBuf::from)
+            .unwrap_or(input_path.with_extension("xiso.iso"));
+        println!("Rewriting {:?} to {:?}", &input_path, &output_path);
+        let files_count = xiso.rewrite(&output_path, skip_update)?;
+        println!("Files written: {}", files_count);
         return Ok(());
     }
 
-    let output_path = cli.out.unwrap_or(input_path.with_extension("").to_string_lossy().to_string());
+    let output_path = cli
+        .out
+        .unwrap_or(input_path.with_extension("").to_string_lossy().to_string());
+
+    #[cfg(all(unix, feature = "fuse"))]
+    if mode.mount {
+        println!("Mounting {:?} at {:?}", &input_path, &output_path);
+        return xiso.mount(&PathBuf::from(output_path));
+    }
+
+    xiso.set_filter(PathFilter::new(&cli.include, &cli.exclude)?);
+    xiso.set_ftp_options(ftp_options);
+
+    if mode.hash {
+        println!("Hashing content of {:?}", &input_path);
+        let manifest = xiso.hash(skip_update)?;
+        for path in manifest.write(&PathBuf::from(output_path))? {
+            println!("Written {:?}", path);
+        }
+        return Ok(());
+    }
+
+    if mode.verify {
+        println!("Verifying {:?} against {:?}", &output_path, &input_path);
+        let report = xiso.verify(&output_path, skip_update)?;
+        if !report.is_ok() {
+            return Err(Error::Mismatch(format!(
+                "Verification failed: {} missing, {} extra, {} mismatched",
+                report.missing.len(),
+                report.extra.len(),
+                report.mismatched.len()
+            )));
+        }
+        return Ok(());
+    }
+
+    if mode.salvage {
+        println!(
+            "Salvaging content of {:?} to {:?}",
+            &input_path, &output_path
+        );
+        let report = xiso.extract_salvage(&output_path, skip_update)?;
+        if !report.is_clean() {
+            return Err(Error::Corrupt(format!(
+                "Image is damaged, affected entries: {}",
+                report.damaged_entries()
+            )));
+        }
+        return Ok(());
+    }
 
     println!(
         "Extracting content of {:?} to {:?}",
@@ -25,7 +165,30 @@ fn main() -> Result<(), String> {
         &output_path
     );
 
+    xiso.set_jobs(cli.jobs as usize);
     xiso.extract_all(&output_path, skip_update)?;
 
     Ok(())
 }
+
+fn ftp_options(cli: &cli::Cli) -> FtpClientBuilder {
+    let mode = match cli.ftp_mode {
+        cli::FtpModeArg::Passive => FtpMode::Passive,
+        cli::FtpModeArg::Epsv => FtpMode::ExtendedPassive,
+        cli::FtpModeArg::Active => FtpMode::Active,
+    };
+    let mut options = FtpClientBuilder::new()
+        .mode(mode)
+        .reconnect_attempts(cli.reconnect)
+        .accept_new_host_key(cli.accept_new_host_key);
+    if let Some(secs) = cli.connect_timeout {
+        options = options.connect_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = cli.timeout {
+        options = options.read_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = cli.keepalive {
+        options = options.keepalive(Duration::from_secs(secs));
+    }
+    options
+}
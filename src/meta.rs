@@ -1,43 +1,250 @@
-use std::io::{Read, Seek, SeekFrom};
+use std::{
+    fmt,
+    io::{Read, Seek, SeekFrom},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use binrw::BinRead;
+use binrw::{binread, BinRead};
+
+use crate::Error;
 
 const HEADER_OFFSET: u64 = 0x10000;
-const OFFSET_XGD3: u64 = 0x2080000;
+const OFFSET_XISO: u64 = 0;
+const OFFSET_XGD1: u64 = 0x18300000;
 const OFFSET_XGD2: u64 = 0xFD90000;
+const OFFSET_XGD3: u64 = 0x2080000;
 pub const SECTOR_SIZE: u32 = 2048;
+/// Magic at the start and the end of the volume descriptor
+pub(crate) const MAGIC: &[u8; 20] = b"MICROSOFT*XBOX*MEDIA";
+
+/// Disc layout the XDVDFS volume was found in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum DiscLayout {
+    /// Rebuilt or trimmed image with the game partition at offset 0
+    #[default]
+    XIso,
+    /// Original Xbox disc
+    Xgd1,
+    /// Xbox 360 disc (XGD2)
+    Xgd2,
+    /// Xbox 360 disc (XGD3)
+    Xgd3,
+}
+
+impl DiscLayout {
+    /// Probing order used by [`get_iso_meta`]
+    const PROBE_ORDER: [DiscLayout; 4] = [
+        DiscLayout::Xgd2,
+        DiscLayout::Xgd3,
+        DiscLayout::Xgd1,
+        DiscLayout::XIso,
+    ];
 
-#[derive(Debug, BinRead)]
-#[br(little, magic = b"MICROSOFT*XBOX*MEDIA")]
+    /// Offset of the game partition in the image
+    pub fn partition_offset(&self) -> u64 {
+        match self {
+            DiscLayout::XIso => OFFSET_XISO,
+            DiscLayout::Xgd1 => OFFSET_XGD1,
+            DiscLayout::Xgd2 => OFFSET_XGD2,
+            DiscLayout::Xgd3 => OFFSET_XGD3,
+        }
+    }
+}
+
+impl fmt::Display for DiscLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscLayout::XIso => write!(f, "XDVDFS (xiso)"),
+            DiscLayout::Xgd1 => write!(f, "XGD1"),
+            DiscLayout::Xgd2 => write!(f, "XGD2"),
+            DiscLayout::Xgd3 => write!(f, "XGD3"),
+        }
+    }
+}
+
+/// XDVDFS volume descriptor, located 0x10000 bytes into the game partition
+#[binread]
+#[derive(Debug)]
+#[br(little)]
 pub struct IsoMeta {
+    #[br(temp, assert(magic == *MAGIC))]
+    magic: [u8; 20],
     pub root_dir_sector: u32,
     pub root_dir_size: u32,
+    /// Volume creation time as Windows FILETIME (100ns ticks since 1601-01-01)
+    #[br(pad_after = 0x7c8)]
+    pub creation_time: u64,
+    /// Trailing magic terminating the descriptor sector
+    #[br(temp, assert(trailer == *MAGIC))]
+    trailer: [u8; 20],
     #[br(ignore)]
     pub root_offset: u64,
+    #[br(ignore)]
+    pub layout: DiscLayout,
 }
 
-pub fn get_iso_meta<R: Read + Seek>(reader: &mut R) -> Result<IsoMeta, String> {
-    let mut root_offset = OFFSET_XGD2;
-    let mut meta;
+impl IsoMeta {
+    /// Volume creation time formatted as UTC date and time
+    pub fn creation_date(&self) -> String {
+        format_filetime(self.creation_time)
+    }
 
-    reader
-        .seek(SeekFrom::Start(root_offset + HEADER_OFFSET))
-        .map_err(|e| format!("Error changing read position: {}", e))?;
-    meta = IsoMeta::read(reader).ok();
+    /// Volume creation time as system time
+    pub fn creation_system_time(&self) -> SystemTime {
+        let secs =
+            (self.creation_time / FILETIME_TICKS_PER_SECOND).saturating_sub(FILETIME_UNIX_OFFSET);
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+}
 
-    if meta.is_none() {
-        root_offset = OFFSET_XGD3;
+pub fn get_iso_meta<R: Read + Seek>(reader: &mut R) -> Result<IsoMeta, Error> {
+    for layout in DiscLayout::PROBE_ORDER {
+        let root_offset = layout.partition_offset();
         reader
             .seek(SeekFrom::Start(root_offset + HEADER_OFFSET))
-            .map_err(|e| format!("Error changing read position: {}", e))?;
-        meta = IsoMeta::read(reader).ok();
+            .map_err(|e| Error::image("Error changing read position", e))?;
+
+        if let Ok(mut meta) = IsoMeta::read(reader) {
+            if meta.root_dir_size == 0 {
+                continue;
+            }
+            meta.root_offset = root_offset;
+            meta.layout = layout;
+            return Ok(meta);
+        }
+    }
+
+    Err(Error::NotXiso)
+}
+
+/// Offset between the FILETIME epoch (1601-01-01) and the unix epoch in seconds
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+const FILETIME_TICKS_PER_SECOND: u64 = 10_000_000;
+
+/// Current time as Windows FILETIME
+pub fn filetime_now() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (since_epoch.as_secs() + FILETIME_UNIX_OFFSET) * FILETIME_TICKS_PER_SECOND
+        + since_epoch.subsec_nanos() as u64 / 100
+}
+
+fn format_filetime(filetime: u64) -> String {
+    let secs = (filetime / FILETIME_TICKS_PER_SECOND).saturating_sub(FILETIME_UNIX_OFFSET);
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    /// Image of zeros with a volume descriptor in the partition at the given offset,
+    /// without holding the hundreds of megabytes in front of it in memory
+    struct SparseImage {
+        descriptor: Vec<u8>,
+        descriptor_offset: u64,
+        len: u64,
+        position: u64,
+    }
+
+    impl SparseImage {
+        fn new(partition_offset: u64, root_dir_size: u32) -> Self {
+            let sector_size = SECTOR_SIZE as usize;
+            let mut descriptor = vec![0; sector_size];
+            descriptor[..20].copy_from_slice(MAGIC);
+            descriptor[20..24].copy_from_slice(&33_u32.to_le_bytes());
+            descriptor[24..28].copy_from_slice(&root_dir_size.to_le_bytes());
+            descriptor[sector_size - 20..].copy_from_slice(MAGIC);
+            let descriptor_offset = partition_offset + HEADER_OFFSET;
+            SparseImage {
+                descriptor,
+                descriptor_offset,
+                len: descriptor_offset + 2 * SECTOR_SIZE as u64,
+                position: 0,
+            }
+        }
     }
 
-    if meta.is_none() {
-        return Err(format!("Unsupported XISO format"));
+    impl Read for SparseImage {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = (buf.len() as u64).min(self.len.saturating_sub(self.position)) as usize;
+            for (index, byte) in buf[..count].iter_mut().enumerate() {
+                let position = self.position + index as u64;
+                *byte = position
+                    .checked_sub(self.descriptor_offset)
+                    .and_then(|offset| self.descriptor.get(offset as usize))
+                    .copied()
+                    .unwrap_or(0);
+            }
+            self.position += count as u64;
+            Ok(count)
+        }
     }
-    let mut meta = meta.unwrap();
-    meta.root_offset = root_offset;
 
-    Ok(meta)
-}
\ No newline at end of file
+    impl Seek for SparseImage {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.position = match pos {
+                SeekFrom::Start(offset) => offset,
+                SeekFrom::End(offset) => self.len.saturating_add_signed(offset),
+                SeekFrom::Current(offset) => self.position.saturating_add_signed(offset),
+            };
+            Ok(self.position)
+        }
+    }
+
+    #[test]
+    fn detects_every_layout() {
+        for layout in DiscLayout::PROBE_ORDER {
+            let mut image = SparseImage::new(layout.partition_offset(), SECTOR_SIZE);
+            let meta = get_iso_meta(&mut image).unwrap();
+            assert_eq!(meta.layout, layout);
+            assert_eq!(meta.root_offset, layout.partition_offset());
+            assert_eq!(meta.root_dir_sector, 33);
+            assert_eq!(meta.root_dir_size, SECTOR_SIZE);
+        }
+    }
+
+    #[test]
+    fn skips_descriptor_without_root_table() {
+        let mut image = SparseImage::new(OFFSET_XGD3, 0);
+        assert!(matches!(get_iso_meta(&mut image), Err(Error::NotXiso)));
+    }
+
+    #[test]
+    fn requires_trailing_magic() {
+        let mut image = SparseImage::new(OFFSET_XISO, SECTOR_SIZE);
+        image.descriptor[SECTOR_SIZE as usize - 1] = b'!';
+        assert!(matches!(get_iso_meta(&mut image), Err(Error::NotXiso)));
+    }
+
+    #[test]
+    fn rejects_other_images() {
+        let mut image = io::Cursor::new(vec![0; 0x20000]);
+        assert!(matches!(get_iso_meta(&mut image), Err(Error::NotXiso)));
+    }
+}
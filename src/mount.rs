@@ -0,0 +1,208 @@
+use std::{
+    ffi::OsStr,
+    io::{Read, Seek, SeekFrom},
+    time::{Duration, SystemTime},
+};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+};
+use libc::{EIO, EISDIR, ENOENT, ENOTDIR};
+
+use crate::{entry::DirEntry, meta::SECTOR_SIZE};
+
+const TTL: Duration = Duration::from_secs(3600);
+const ROOT_INODE: u64 = 1;
+
+struct Inode {
+    name: String,
+    parent: u64,
+    sector: u32,
+    size: u32,
+    children: Option<Vec<u64>>,
+}
+
+/// Read-only FUSE filesystem serving the directory tree of an image.
+/// Inode numbers are indices into `inodes` plus one, the root directory is inode 1.
+pub(crate) struct XIsoFs<R: Read + Seek> {
+    reader: R,
+    root_offset: u64,
+    creation_time: SystemTime,
+    inodes: Vec<Inode>,
+}
+
+impl<R: Read + Seek> XIsoFs<R> {
+    pub fn new(reader: R, root_offset: u64, creation_time: SystemTime, root: &[DirEntry]) -> Self {
+        let mut fs = XIsoFs {
+            reader,
+            root_offset,
+            creation_time,
+            inodes: vec![Inode {
+                name: String::new(),
+                parent: ROOT_INODE,
+                sector: 0,
+                size: 0,
+                children: None,
+            }],
+        };
+        let children = fs.add_entries(root, ROOT_INODE);
+        fs.inodes[0].children = Some(children);
+        fs
+    }
+
+    fn add_entries(&mut self, entries: &[DirEntry], parent: u64) -> Vec<u64> {
+        let mut inodes = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            self.inodes.push(Inode {
+                name: entry.name.clone(),
+                parent,
+                sector: entry.sector,
+                size: entry.size,
+                children: None,
+            });
+            let ino = self.inodes.len() as u64;
+            if let Some(subdir) = &entry.subdir {
+                let children = self.add_entries(subdir, ino);
+                self.inodes[ino as usize - 1].children = Some(children);
+            }
+            inodes.push(ino);
+        }
+        inodes
+    }
+
+    fn inode(&self, ino: u64) -> Option<&Inode> {
+        self.inodes.get((ino as usize).checked_sub(1)?)
+    }
+
+    fn attr(&self, ino: u64, inode: &Inode, req: &Request<'_>) -> FileAttr {
+        let (kind, perm, size) = match inode.children {
+            Some(_) => (FileType::Directory, 0o555, 0),
+            None => (FileType::RegularFile, 0o444, inode.size as u64),
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.creation_time,
+            mtime: self.creation_time,
+            ctime: self.creation_time,
+            crtime: self.creation_time,
+            kind,
+            perm,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: req.uid(),
+            gid: req.gid(),
+            rdev: 0,
+            blksize: SECTOR_SIZE,
+            flags: 0,
+        }
+    }
+
+    fn read_data(&mut self, sector: u32, offset: u64, size: usize) -> std::io::Result<Vec<u8>> {
+        let position = self.root_offset + sector as u64 * SECTOR_SIZE as u64 + offset;
+        self.reader.seek(SeekFrom::Start(position))?;
+        let mut buffer = vec![0; size];
+        self.reader.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+impl<R: Read + Seek> Filesystem for XIsoFs<R> {
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let Some(children) = self.inode(parent).and_then(|i| i.children.as_ref()) else {
+            reply.error(ENOTDIR);
+            return;
+        };
+        let name = name.to_string_lossy();
+        let found = children.iter().find(|&&ino| {
+            self.inodes[ino as usize - 1]
+                .name
+                .eq_ignore_ascii_case(&name)
+        });
+        match found {
+            Some(&ino) => reply.entry(
+                &TTL,
+                &self.attr(ino, &self.inodes[ino as usize - 1], req),
+                0,
+            ),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.inode(ino) {
+            Some(inode) => reply.attr(&TTL, &self.attr(ino, inode, req)),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(inode) = self.inode(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        if inode.children.is_some() {
+            reply.error(EISDIR);
+            return;
+        }
+        let offset = offset.max(0) as u64;
+        let file_size = inode.size as u64;
+        if offset >= file_size {
+            reply.data(&[]);
+            return;
+        }
+        let count = (size as u64).min(file_size - offset) as usize;
+        match self.read_data(inode.sector, offset, count) {
+            Ok(data) => reply.data(&data),
+            Err(_) => reply.error(EIO),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(inode) = self.inode(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let Some(children) = inode.children.as_ref() else {
+            reply.error(ENOTDIR);
+            return;
+        };
+
+        let mut items = vec![
+            (ino, FileType::Directory, "."),
+            (inode.parent, FileType::Directory, ".."),
+        ];
+        for &child in children.iter() {
+            let child_inode = &self.inodes[child as usize - 1];
+            let kind = match child_inode.children {
+                Some(_) => FileType::Directory,
+                None => FileType::RegularFile,
+            };
+            items.push((child, kind, child_inode.name.as_str()));
+        }
+
+        for (i, (ino, kind, name)) in items.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    fs::File,
+    io::{BufReader, Stdout},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use pbr::{ProgressBar, Units};
+
+use crate::{
+    copy_entry,
+    entry::DirEntry,
+    entry_reader::EntryReader,
+    ftp::FtpClientBuilder,
+    meta::SECTOR_SIZE,
+    resume_offset,
+    sink::{self, OutputSink},
+    with_reconnect, Error,
+};
+
+/// State shared by the workers of a parallel extraction
+struct Transfer<'a> {
+    files: Vec<(String, &'a DirEntry)>,
+    next: AtomicUsize,
+    done: AtomicUsize,
+    failed: AtomicBool,
+    error: Mutex<Option<Error>>,
+    pb: Mutex<ProgressBar<Stdout>>,
+}
+
+impl Transfer<'_> {
+    fn advance(&self, bytes: u64) {
+        self.pb.lock().unwrap().add(bytes);
+    }
+
+    fn rewind(&self, bytes: u64) {
+        let mut pb = self.pb.lock().unwrap();
+        let current = pb.add(0);
+        pb.set(current - bytes);
+    }
+}
+
+/// Extracts the entries using `jobs` connections to the output location, each worker
+/// reading from its own handle of the image. Directories are created upfront on a
+/// separate connection, the files are then handed out to the workers one at a time.
+/// Returns the number of files extracted.
+pub(crate) fn extract(
+    image_path: &Path,
+    root_offset: u64,
+    out_path: &str,
+    ftp_options: &FtpClientBuilder,
+    entries: &[DirEntry],
+    jobs: usize,
+) -> Result<u32, Error> {
+    let mut files = Vec::new();
+    {
+        let mut sink = sink::open(out_path, ftp_options, false)?;
+        sink.prepare()?;
+        create_dirs(sink.as_mut(), entries, "", &mut files)?;
+        sink.finish()?;
+    }
+
+    let total_size = files.iter().map(|(_, entry)| entry.size as u64).sum();
+    let mut pb = ProgressBar::new(total_size);
+    pb.set_units(Units::Bytes);
+    pb.message(format!("0/{} files: ", files.len()).as_str());
+
+    let transfer = Transfer {
+        files,
+        next: AtomicUsize::new(0),
+        done: AtomicUsize::new(0),
+        failed: AtomicBool::new(false),
+        error: Mutex::new(None),
+        pb: Mutex::new(pb),
+    };
+
+    let workers = jobs.min(transfer.files.len());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                let result = run_worker(&transfer, image_path, root_offset, out_path, ftp_options);
+                if let Err(e) = result {
+                    transfer.failed.store(true, Ordering::SeqCst);
+                    transfer.error.lock().unwrap().get_or_insert(e);
+                }
+            });
+        }
+    });
+
+    if let Some(e) = transfer.error.into_inner().unwrap() {
+        return Err(e);
+    }
+    transfer.pb.into_inner().unwrap().finish_print(out_path);
+    Ok(transfer.files.len() as u32)
+}
+
+/// Creates the directory structure and collects the files with their output paths
+fn create_dirs<'a>(
+    sink: &mut dyn OutputSink,
+    entries: &'a [DirEntry],
+    parent: &str,
+    files: &mut Vec<(String, &'a DirEntry)>,
+) -> Result<(), Error> {
+    for entry in entries.iter() {
+        let path = sink::join_path(parent, &entry.name);
+        if entry.is_dir() {
+            if !sink.exists(&path)? {
+                sink.create_dir(&path)?
+            }
+            if let Some(entries) = &entry.subdir {
+                create_dirs(sink, entries, &path, files)?;
+            }
+        } else {
+            files.push((path, entry));
+        }
+    }
+    Ok(())
+}
+
+fn run_worker(
+    transfer: &Transfer,
+    image_path: &Path,
+    root_offset: u64,
+    out_path: &str,
+    ftp_options: &FtpClientBuilder,
+) -> Result<(), Error> {
+    let file = File::open(image_path).map_err(|e| Error::io("Error opening input file", e))?;
+    let mut reader = BufReader::new(file);
+    let mut sink = sink::open(out_path, ftp_options, false)?;
+
+    while !transfer.failed.load(Ordering::SeqCst) {
+        let index = transfer.next.fetch_add(1, Ordering::SeqCst);
+        let Some((out_file, entry)) = transfer.files.get(index) else {
+            break;
+        };
+
+        // bytes of the file counted in the progress, taken back if the transfer restarts
+        let mut reported = 0;
+        with_reconnect(sink.as_mut(), |sink| {
+            let offset = resume_offset(sink, entry, out_file)?;
+            transfer.rewind(reported);
+            reported = offset.unwrap_or(entry.size as u64);
+            transfer.advance(reported);
+
+            let Some(offset) = offset else {
+                return Ok(());
+            };
+            let position = root_offset + entry.sector as u64 * SECTOR_SIZE as u64;
+            let mut entry_reader = EntryReader::new(&mut reader, position, entry.size as u64)
+                .map_err(|e| {
+                    Error::image(
+                        format!("Unable to jump to record at {}. Broken ISO?", position),
+                        e,
+                    )
+                })?;
+            copy_entry(&mut entry_reader, sink, out_file, offset, &mut |written| {
+                transfer.advance(written);
+                reported += written;
+            })
+        })?;
+
+        let done = transfer.done.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut pb = transfer.pb.lock().unwrap();
+        pb.message(format!("{}/{} files: ", done, transfer.files.len()).as_str());
+        pb.tick();
+    }
+    Ok(())
+}
@@ -0,0 +1,441 @@
+use std::{
+    cmp::min,
+    collections::HashSet,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+};
+
+use pbr::{ProgressBar, Units};
+
+use crate::{
+    entry::DirEntry,
+    meta::SECTOR_SIZE,
+    sink::{self, OutputSink},
+    with_reconnect, Error, XIso, BUFFER_SIZE,
+};
+
+/// Part of the image which could not be read, and the entry it belongs to
+#[derive(Debug, Clone)]
+pub struct Damage {
+    /// Path of the affected file or directory inside the image
+    pub path: String,
+    /// Affected sectors relative to the start of the game partition
+    pub sectors: Range<u32>,
+    pub reason: String,
+}
+
+impl Damage {
+    /// Sectors holding `size` bytes at `offset` into data starting at `sector`.
+    /// Sectors past the last addressable one are cut off.
+    pub(crate) fn sector_range(sector: u32, offset: u64, size: u64) -> Range<u32> {
+        let to_sector = |bytes: u64| {
+            u32::try_from(sector as u64 + bytes / SECTOR_SIZE as u64).unwrap_or(u32::MAX)
+        };
+        to_sector(offset)..to_sector((offset + size).next_multiple_of(SECTOR_SIZE as u64))
+    }
+}
+
+/// Result of a salvage extraction, see [`XIso::extract_salvage`]
+#[derive(Debug, Default)]
+pub struct DamageReport {
+    /// Number of files written, including damaged ones
+    pub extracted: u32,
+    /// Unreadable directory tables and file data, in the order they were found
+    pub damaged: Vec<Damage>,
+}
+
+impl DamageReport {
+    pub fn is_clean(&self) -> bool {
+        self.damaged.is_empty()
+    }
+
+    /// Number of distinct files and directories with damage
+    pub fn damaged_entries(&self) -> usize {
+        let paths: HashSet<&str> = self.damaged.iter().map(|d| d.path.as_str()).collect();
+        paths.len()
+    }
+}
+
+impl<R: Read + Seek> XIso<R> {
+    /// Extracts the selected entries like [`XIso::extract_all`], but continues past
+    /// read errors and the end of a truncated image. Unreadable file data is
+    /// written as zeros, files are always written anew and one at a time.
+    /// The report includes the directory tables found broken when opening the
+    /// image with [`XIso::from_path_salvage`].
+    pub fn extract_salvage(
+        &mut self,
+        out_path: &str,
+        skip_update: bool,
+    ) -> Result<DamageReport, Error> {
+        let mut sink = sink::open(out_path, &self.ftp_options, false)?;
+        self.extract_salvage_to(sink.as_mut(), skip_update)
+    }
+
+    /// Salvages the content of the image into a custom output sink
+    pub fn extract_salvage_to(
+        &mut self,
+        sink: &mut dyn OutputSink,
+        skip_update: bool,
+    ) -> Result<DamageReport, Error> {
+        let entries = self.selected_entries(skip_update);
+        let mut report = DamageReport {
+            extracted: 0,
+            damaged: self.damage.clone(),
+        };
+
+        sink.prepare()?;
+        self.salvage_dir(sink, &entries, "", &mut report)?;
+        sink.finish()?;
+        println!();
+        println!("Files extracted: {}", report.extracted);
+
+        if !report.is_clean() {
+            println!();
+            println!("Damage report:");
+            for damage in report.damaged.iter() {
+                println!(
+                    "  {}: sectors {}-{}, {}",
+                    damage.path,
+                    damage.sectors.start,
+                    damage
+                        .sectors
+                        .end
+                        .saturating_sub(1)
+                        .max(damage.sectors.start),
+                    damage.reason
+                );
+            }
+            println!(
+                "Damaged entries: {}, damaged sectors: {}",
+                report.damaged_entries(),
+                report
+                    .damaged
+                    .iter()
+                    .map(|d| d.sectors.len())
+                    .sum::<usize>()
+            );
+        }
+        Ok(report)
+    }
+
+    fn salvage_dir(
+        &mut self,
+        sink: &mut dyn OutputSink,
+        entries: &[DirEntry],
+        parent: &str,
+        report: &mut DamageReport,
+    ) -> Result<(), Error> {
+        for entry in entries.iter() {
+            let path = sink::join_path(parent, &entry.name);
+            if entry.is_dir() {
+                with_reconnect(sink, |sink| {
+                    if !sink.exists(&path)? {
+                        sink.create_dir(&path)?
+                    }
+                    Ok(())
+                })?;
+                if let Some(entries) = &entry.subdir {
+                    self.salvage_dir(sink, entries, &path, report)?;
+                }
+            } else {
+                let damaged = with_reconnect(sink, |sink| self.salvage_file(sink, entry, &path))?;
+                report.damaged.extend(damaged);
+                report.extracted += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the file data chunk by chunk, replacing what can not be read with
+    /// zeros. Returns the unreadable sector ranges.
+    fn salvage_file(
+        &mut self,
+        sink: &mut dyn OutputSink,
+        entry: &DirEntry,
+        out_file: &str,
+    ) -> Result<Vec<Damage>, Error> {
+        let entry_size = entry.size as u64;
+        let start = self.meta.root_offset + entry.sector as u64 * SECTOR_SIZE as u64;
+
+        let mut pb = ProgressBar::new(entry_size);
+        pb.set_units(Units::Bytes);
+        pb.message(format!("{}: ", &entry.name).as_str());
+        pb.show_speed = false;
+        pb.show_time_left = false;
+
+        let mut damaged: Vec<Damage> = Vec::new();
+        let mut writer = sink.create_file(out_file, entry_size)?;
+        let mut buffer = vec![0; min(entry_size, BUFFER_SIZE as u64) as usize];
+        let mut position = 0_u64;
+        // the read position is unknown after a failed read
+        let mut seek = true;
+        while position < entry_size {
+            let chunk_size = min(entry_size - position, buffer.len() as u64) as usize;
+            let chunk = &mut buffer[..chunk_size];
+            let (filled, error) = read_chunk(&mut self.reader, start + position, chunk, seek);
+            seek = error.is_some();
+            if let Some(reason) = error {
+                chunk[filled..].fill(0);
+                let sectors = Damage::sector_range(
+                    entry.sector,
+                    position + filled as u64,
+                    (chunk_size - filled) as u64,
+                );
+                match damaged.last_mut() {
+                    Some(last) if last.sectors.end >= sectors.start && last.reason == reason => {
+                        last.sectors.end = sectors.end
+                    }
+                    _ => damaged.push(Damage {
+                        path: format!("/{}", out_file),
+                        sectors,
+                        reason,
+                    }),
+                }
+            }
+            writer
+                .write_all(chunk)
+                .map_err(|e| Error::io(format!("Error writing to file {:?}", out_file), e))?;
+            position += chunk_size as u64;
+            pb.add(chunk_size as u64);
+        }
+
+        drop(writer);
+        sink.finalize(out_file, entry_size)?;
+        if damaged.is_empty() {
+            pb.finish_print(&sink.display_path(out_file));
+        } else {
+            pb.finish_print(&format!("{} (damaged)", sink.display_path(out_file)));
+        }
+        println!();
+        Ok(damaged)
+    }
+}
+
+/// Fills the chunk from the image at the given position as far as possible.
+/// Returns the number of bytes read and the reason if that is not all of them.
+fn read_chunk<R: Read + Seek>(
+    reader: &mut R,
+    position: u64,
+    chunk: &mut [u8],
+    seek: bool,
+) -> (usize, Option<String>) {
+    if seek {
+        if let Err(e) = reader.seek(SeekFrom::Start(position)) {
+            return (0, Some(e.to_string()));
+        }
+    }
+    let mut filled = 0;
+    while filled < chunk.len() {
+        match reader.read(&mut chunk[filled..]) {
+            Ok(0) => return (filled, Some("image is truncated".to_string())),
+            Ok(count) => filled += count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return (filled, Some(e.to_string())),
+        }
+    }
+    (filled, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor, path::PathBuf};
+
+    use super::*;
+    use crate::{
+        entry::ParseStrategy,
+        writer::{self, Node},
+    };
+
+    /// Image with a 20000 byte file of 0xaa and a 10000 byte file of 0xbb
+    fn image() -> Vec<u8> {
+        let mut root = vec![
+            Node::file("a.bin".to_string(), 20000, fill("a.bin")),
+            Node::file("b.bin".to_string(), 10000, fill("b.bin")),
+        ];
+        let mut image = Vec::new();
+        writer::write_image(&mut image, &mut root, 0, |node, image| {
+            image
+                .write_all(&vec![node.source; node.size as usize])
+                .map_err(|e| Error::io("Error writing image", e))
+        })
+        .unwrap();
+        image
+    }
+
+    fn fill(name: &str) -> u8 {
+        match name {
+            "a.bin" => 0xaa,
+            _ => 0xbb,
+        }
+    }
+
+    /// Reader failing on reads which start inside one of the bad ranges, reads in
+    /// front of a bad range stop at its start
+    struct FailingReader {
+        inner: Cursor<Vec<u8>>,
+        bad: Vec<Range<u64>>,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let position = self.inner.position();
+            if self.bad.iter().any(|bad| bad.contains(&position)) {
+                return Err(io::Error::other("bad sector"));
+            }
+            let limit = self
+                .bad
+                .iter()
+                .filter(|bad| bad.start > position)
+                .map(|bad| (bad.start - position) as usize)
+                .fold(buf.len(), usize::min);
+            self.inner.read(&mut buf[..limit])
+        }
+    }
+
+    impl Seek for FailingReader {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    /// Sector and image offset of a file in the root directory
+    fn locate<R: Read + Seek>(xiso: &XIso<R>, name: &str) -> (u32, u64) {
+        let entry = xiso.root.iter().find(|e| e.name == name).unwrap();
+        (entry.sector, entry.sector as u64 * SECTOR_SIZE as u64)
+    }
+
+    fn out_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("xiso-ex-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn sector_ranges() {
+        assert_eq!(Damage::sector_range(10, 0, 1), 10..11);
+        assert_eq!(Damage::sector_range(10, 2048, 2048), 11..12);
+        assert_eq!(Damage::sector_range(10, 100, 3000), 10..12);
+        assert_eq!(Damage::sector_range(10, 4096, 0), 12..12);
+        assert_eq!(
+            Damage::sector_range(u32::MAX - 1, 0, 3 * SECTOR_SIZE as u64),
+            u32::MAX - 1..u32::MAX
+        );
+    }
+
+    #[test]
+    fn read_chunk_stops_at_errors() {
+        let mut reader = Cursor::new(vec![1; 100]);
+        let mut chunk = [0; 64];
+        assert_eq!(read_chunk(&mut reader, 0, &mut chunk, true), (64, None));
+        assert_eq!(
+            read_chunk(&mut reader, 64, &mut chunk, false),
+            (36, Some("image is truncated".to_string()))
+        );
+
+        let mut reader = FailingReader {
+            inner: Cursor::new(vec![1; 100]),
+            bad: vec![40..50, 90..95],
+        };
+        let (filled, error) = read_chunk(&mut reader, 0, &mut chunk, true);
+        assert_eq!(filled, 40);
+        assert_eq!(error.as_deref(), Some("bad sector"));
+        let (filled, error) = read_chunk(&mut reader, 50, &mut chunk, true);
+        assert_eq!(filled, 40);
+        assert_eq!(error.as_deref(), Some("bad sector"));
+        assert_eq!(
+            read_chunk(&mut reader, 95, &mut chunk[..5], true),
+            (5, None)
+        );
+    }
+
+    #[test]
+    fn damaged_entries_are_counted_once() {
+        let damage = |path: &str, sectors| Damage {
+            path: path.to_string(),
+            sectors,
+            reason: "bad sector".to_string(),
+        };
+        let report = DamageReport {
+            extracted: 3,
+            damaged: vec![damage("/a", 1..2), damage("/a", 5..6), damage("/b", 9..10)],
+        };
+        assert!(!report.is_clean());
+        assert_eq!(report.damaged_entries(), 2);
+        assert!(DamageReport::default().is_clean());
+    }
+
+    #[test]
+    fn truncated_image() {
+        let mut image = image();
+        let xiso = XIso::from_reader(Cursor::new(image.clone())).unwrap();
+        // cut the image in the middle of the file stored last
+        let (name, other) = match locate(&xiso, "a.bin") > locate(&xiso, "b.bin") {
+            true => ("a.bin", "b.bin"),
+            false => ("b.bin", "a.bin"),
+        };
+        let (sector, offset) = locate(&xiso, name);
+        image.truncate(offset as usize + 5000);
+
+        let out = out_dir("truncated");
+        let mut xiso =
+            XIso::from_reader_salvage(Cursor::new(image), ParseStrategy::default()).unwrap();
+        let report = xiso.extract_salvage(out.to_str().unwrap(), false).unwrap();
+
+        assert_eq!(report.extracted, 2);
+        assert_eq!(report.damaged_entries(), 1);
+        assert_eq!(report.damaged.len(), 1);
+        let damage = &report.damaged[0];
+        assert_eq!(damage.path, format!("/{}", name));
+        assert_eq!(damage.reason, "image is truncated");
+        let size = xiso.root.iter().find(|e| e.name == name).unwrap().size as u64;
+        assert_eq!(
+            damage.sectors,
+            Damage::sector_range(sector, 5000, size - 5000)
+        );
+
+        let content = fs::read(out.join(name)).unwrap();
+        assert_eq!(content.len() as u64, size);
+        assert!(content[..5000].iter().all(|&b| b == fill(name)));
+        assert!(content[5000..].iter().all(|&b| b == 0));
+        assert!(fs::read(out.join(other))
+            .unwrap()
+            .iter()
+            .all(|&b| b == fill(other)));
+        fs::remove_dir_all(&out).unwrap();
+    }
+
+    #[test]
+    fn read_errors() {
+        let image = image();
+        let xiso = XIso::from_reader(Cursor::new(image.clone())).unwrap();
+        let (sector, offset) = locate(&xiso, "a.bin");
+        // second and fourth chunk of a.bin
+        let reader = FailingReader {
+            inner: Cursor::new(image),
+            bad: vec![offset + 4096..offset + 4100, offset + 12288..offset + 12290],
+        };
+
+        let out = out_dir("read-errors");
+        let mut xiso = XIso::from_reader_salvage(reader, ParseStrategy::default()).unwrap();
+        let report = xiso.extract_salvage(out.to_str().unwrap(), false).unwrap();
+
+        assert_eq!(report.extracted, 2);
+        assert_eq!(report.damaged_entries(), 1);
+        let sectors: Vec<Range<u32>> = report.damaged.iter().map(|d| d.sectors.clone()).collect();
+        assert_eq!(sectors, [sector + 2..sector + 4, sector + 6..sector + 8]);
+        assert!(report
+            .damaged
+            .iter()
+            .all(|d| d.path == "/a.bin" && d.reason == "bad sector"));
+
+        let content = fs::read(out.join("a.bin")).unwrap();
+        assert_eq!(content.len(), 20000);
+        for (index, chunk) in content.chunks(4096).enumerate() {
+            let expected = if index == 1 || index == 3 { 0 } else { 0xaa };
+            assert!(chunk.iter().all(|&b| b == expected), "chunk {}", index);
+        }
+        assert!(fs::read(out.join("b.bin"))
+            .unwrap()
+            .iter()
+            .all(|&b| b == 0xbb));
+        fs::remove_dir_all(&out).unwrap();
+    }
+}
@@ -0,0 +1,371 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp};
+
+use crate::{
+    credentials::Credentials,
+    ftp::{parse_url, FtpClientBuilder},
+    sink::{verify_size, OutputSink},
+    Error,
+};
+
+/// libssh2 status of a missing path
+const SFTP_NO_SUCH_FILE: i32 = 2;
+/// Larger requests mean fewer round trips, SFTP acknowledges every packet
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Uploads over SFTP, the output root is the path of the url.
+/// The host key of the server is checked against `~/.ssh/known_hosts`.
+/// Authenticates with the configured password, or else with the ssh agent or
+/// the default key files in `~/.ssh`.
+pub struct SftpSink {
+    _session: Session,
+    sftp: Sftp,
+    url: String,
+    options: FtpClientBuilder,
+    root: String,
+    writer: Option<BufWriter<ssh2::File>>,
+    reconnects_left: u32,
+}
+
+impl SftpSink {
+    /// Connects to the server of an sftp:// url with the timeouts, keepalive interval
+    /// and reconnect attempts of `options`. A server missing from `~/.ssh/known_hosts`
+    /// is refused unless accepting new host keys is enabled, then its key is added to
+    /// the file.
+    pub fn connect(url: &str, options: &FtpClientBuilder) -> Result<Self, Error> {
+        let url_str = url;
+        let url = parse_url(url)?;
+        let host = url.host_str().ok_or(Error::Invalid(format!(
+            "Missing host in sftp url {:?}",
+            &url
+        )))?;
+        let port = url.port_or_known_default().unwrap_or(22);
+        let credentials = Credentials::lookup(&url)?;
+        let user = credentials
+            .user
+            .or_else(|| std::env::var("USER").ok())
+            .unwrap_or("root".to_string());
+
+        let tcp = options
+            .connect_tcp(&host, port as u16)
+            .map_err(|e| Error::sftp(format!("Error connecting to sftp server {}", &host), e))?;
+        let mut session =
+            Session::new().map_err(|e| Error::sftp("Error creating ssh session", e))?;
+        session.set_tcp_stream(tcp);
+        // libssh2 does not use the socket timeout, blocking calls wait forever by default
+        if let Some(timeout) = options.get_read_timeout() {
+            session.set_timeout(timeout.as_millis().clamp(1, u32::MAX as u128) as u32);
+        }
+        session
+            .handshake()
+            .map_err(|e| Error::sftp(format!("Error connecting to sftp server {}", &host), e))?;
+        // the connection succeeded, so the port is valid
+        verify_host_key(
+            &session,
+            &host,
+            port as u16,
+            options.get_accept_new_host_key(),
+        )?;
+
+        authenticate(&session, &user, credentials.password).map_err(|e| Error::Auth {
+            server: format!("sftp server {}", &host),
+            user: user.clone(),
+            source: Box::new(e),
+        })?;
+
+        let sftp = session
+            .sftp()
+            .map_err(|e| Error::sftp(format!("Error starting sftp session on {}", &host), e))?;
+        let root = format!("/{}", url.path_segments().unwrap_or_default().join("/"));
+
+        Ok(SftpSink {
+            _session: session,
+            sftp,
+            url: url_str.to_string(),
+            options: options.clone(),
+            root,
+            writer: None,
+            reconnects_left: options.get_reconnect_attempts(),
+        })
+    }
+
+    /// Whether the connection broke. libssh2 reports most socket failures of file
+    /// writes as generic errors, so the session is probed if the error does not tell.
+    fn connection_lost(&self, error: &Error) -> bool {
+        error.is_connection_error()
+            || (matches!(error, Error::Io { .. } | Error::Sftp { .. })
+                && self.sftp.stat(Path::new(&self.root)).is_err())
+    }
+
+    fn remote_path(&self, path: &str) -> String {
+        format!("{}/{}", self.root.trim_end_matches('/'), path)
+    }
+
+    /// Attributes of the remote path, `None` if it does not exist
+    fn stat(&self, path: &str) -> Result<Option<ssh2::FileStat>, Error> {
+        match self.sftp.stat(Path::new(path)) {
+            Ok(stat) => Ok(Some(stat)),
+            Err(e) if e.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => Ok(None),
+            Err(e) => Err(Error::sftp(
+                format!("Error reading '{}' on sftp server", path),
+                e,
+            )),
+        }
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), Error> {
+        self.sftp.mkdir(Path::new(path), 0o755).map_err(|e| {
+            Error::sftp(
+                format!("Error creating directory '{}' on sftp server", path),
+                e,
+            )
+        })
+    }
+}
+
+fn ssh_dir() -> PathBuf {
+    std::env::var("HOME")
+        .map(|home| PathBuf::from(home).join(".ssh"))
+        .unwrap_or_default()
+}
+
+/// Checks the key the server presented during the handshake against
+/// `~/.ssh/known_hosts`, like ssh with `StrictHostKeyChecking` set to `yes`, or to
+/// `accept-new` if unknown keys are accepted
+fn verify_host_key(
+    session: &Session,
+    host: &str,
+    port: u16,
+    accept_new: bool,
+) -> Result<(), Error> {
+    let (key, key_type) = session.host_key().ok_or(Error::sftp(
+        format!("Error connecting to sftp server {}", host),
+        "server sent no host key",
+    ))?;
+    let known_hosts_error = |e| Error::sftp("Error reading ~/.ssh/known_hosts", e);
+    let mut known_hosts = session.known_hosts().map_err(known_hosts_error)?;
+    let known_hosts_path = ssh_dir().join("known_hosts");
+    if known_hosts_path.exists() {
+        known_hosts
+            .read_file(&known_hosts_path, KnownHostFileKind::OpenSSH)
+            .map_err(known_hosts_error)?;
+    }
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(Error::sftp(
+            format!("Host key of sftp server {} has changed", host),
+            "the key does not match ~/.ssh/known_hosts, the connection may be intercepted",
+        )),
+        CheckResult::NotFound if accept_new => {
+            // ssh records servers on other ports as [host]:port
+            let entry = match port {
+                22 => host.to_string(),
+                _ => format!("[{}]:{}", host, port),
+            };
+            known_hosts
+                .add(&entry, key, "", key_type.into())
+                .map_err(known_hosts_error)?;
+            let line = known_hosts
+                .iter()
+                .ok()
+                .and_then(|hosts| hosts.into_iter().last())
+                .map(|added| known_hosts.write_string(&added, KnownHostFileKind::OpenSSH))
+                .transpose()
+                .map_err(known_hosts_error)?
+                .unwrap_or_default();
+            append_known_host(&known_hosts_path, &line)
+                .map_err(|e| Error::io(format!("Error writing to {:?}", &known_hosts_path), e))?;
+            println!("Added host key of {} to {:?}", &entry, &known_hosts_path);
+            Ok(())
+        }
+        CheckResult::NotFound => Err(Error::sftp(
+            format!("Host key of sftp server {} is unknown", host),
+            "the server is not in ~/.ssh/known_hosts, use --accept-new-host-key to trust it",
+        )),
+        CheckResult::Failure => Err(Error::sftp(
+            format!("Error checking the host key of sftp server {}", host),
+            "unsupported key type",
+        )),
+    }
+}
+
+fn append_known_host(path: &Path, line: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())
+}
+
+fn authenticate(
+    session: &Session,
+    user: &str,
+    password: Option<String>,
+) -> Result<(), ssh2::Error> {
+    if let Some(password) = password {
+        return session.userauth_password(user, &password);
+    }
+    if session.userauth_agent(user).is_ok() {
+        return Ok(());
+    }
+
+    let ssh_dir = ssh_dir();
+    let mut result = Err(ssh2::Error::new(
+        ErrorCode::Session(-18),
+        "no password given and no usable key found",
+    ));
+    for key in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+        let key_path = ssh_dir.join(key);
+        if key_path.exists() {
+            result = session.userauth_pubkey_file(user, None, &key_path, None);
+            if result.is_ok() {
+                break;
+            }
+        }
+    }
+    result
+}
+
+impl OutputSink for SftpSink {
+    fn prepare(&mut self) -> Result<(), Error> {
+        let mut cur_dir = String::new();
+        for segment in self.root.split('/').filter(|s| !s.is_empty()) {
+            cur_dir = format!("{}/{}", cur_dir, segment);
+            if self.stat(&cur_dir)?.is_none() {
+                self.mkdir(&cur_dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn exists(&mut self, path: &str) -> Result<bool, Error> {
+        Ok(self.stat(&self.remote_path(path))?.is_some())
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), Error> {
+        self.mkdir(&self.remote_path(path))
+    }
+
+    fn file_size(&mut self, path: &str) -> Result<Option<u64>, Error> {
+        let stat = self.stat(&self.remote_path(path))?;
+        Ok(stat.filter(|s| s.is_file()).map(|s| s.size.unwrap_or(0)))
+    }
+
+    fn create_file(&mut self, path: &str, _size: u64) -> Result<Box<dyn Write + '_>, Error> {
+        let remote_path = self.remote_path(path);
+        let file = self.sftp.create(Path::new(&remote_path)).map_err(|e| {
+            Error::sftp(
+                format!("Error creating file '{}' on sftp server", &remote_path),
+                e,
+            )
+        })?;
+        let writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+        Ok(Box::new(self.writer.insert(writer)))
+    }
+
+    fn supports_resume(&self) -> bool {
+        true
+    }
+
+    fn append_file(&mut self, path: &str, offset: u64) -> Result<Box<dyn Write + '_>, Error> {
+        let remote_path = self.remote_path(path);
+        let mut file = self
+            .sftp
+            .open_mode(
+                Path::new(&remote_path),
+                OpenFlags::WRITE,
+                0o644,
+                OpenType::File,
+            )
+            .map_err(|e| {
+                Error::sftp(
+                    format!("Error opening file '{}' on sftp server", &remote_path),
+                    e,
+                )
+            })?;
+        file.seek(SeekFrom::Start(offset)).map_err(|e| {
+            Error::sftp(
+                format!("Error opening file '{}' on sftp server", &remote_path),
+                e,
+            )
+        })?;
+        let writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+        Ok(Box::new(self.writer.insert(writer)))
+    }
+
+    fn finalize(&mut self, path: &str, size: u64) -> Result<(), Error> {
+        let remote_path = self.remote_path(path);
+        if let Some(writer) = self.writer.take() {
+            let mut file = writer.into_inner().map_err(|e| {
+                Error::sftp(
+                    format!("Error writing file '{}'", &remote_path),
+                    e.into_error(),
+                )
+            })?;
+            file.close()
+                .map_err(|e| Error::sftp(format!("Error closing file '{}'", &remote_path), e))?;
+        }
+        let file_size = self.file_size(path)?.unwrap_or(0);
+        verify_size(&remote_path, file_size, size)
+    }
+
+    fn read_file(
+        &mut self,
+        path: &str,
+        reader: &mut dyn FnMut(&mut dyn Read) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let remote_path = self.remote_path(path);
+        let file = self.sftp.open(Path::new(&remote_path)).map_err(|e| {
+            Error::sftp(
+                format!("Error opening file '{}' on sftp server", &remote_path),
+                e,
+            )
+        })?;
+        reader(&mut BufReader::with_capacity(BUFFER_SIZE, file))
+    }
+
+    fn list_dir(&mut self, path: &str) -> Result<Vec<(String, bool)>, Error> {
+        let remote_path = self.remote_path(path);
+        let entries = self.sftp.readdir(Path::new(&remote_path)).map_err(|e| {
+            Error::sftp(
+                format!("Error reading directory '{}' on sftp server", &remote_path),
+                e,
+            )
+        })?;
+        Ok(entries
+            .iter()
+            .filter_map(|(path, stat)| {
+                let name = path.file_name()?.to_string_lossy().to_string();
+                Some((name, stat.is_dir()))
+            })
+            .collect())
+    }
+
+    fn reconnect(&mut self, error: &Error) -> Result<bool, Error> {
+        if self.reconnects_left == 0 || !self.connection_lost(error) {
+            return Ok(false);
+        }
+        self.reconnects_left -= 1;
+        println!();
+        println!("{}", error.report());
+        println!(
+            "Reconnecting to sftp server ({} attempts left).",
+            self.reconnects_left
+        );
+        self.writer = None;
+        let reconnected = SftpSink::connect(&self.url, &self.options)?;
+        self._session = reconnected._session;
+        self.sftp = reconnected.sftp;
+        Ok(true)
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        self.remote_path(path)
+    }
+}
@@ -0,0 +1,358 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::PathBuf,
+};
+
+use crate::{
+    archive::{TarSink, ZipSink},
+    ftp::{FtpClient, FtpClientBuilder},
+    sftp::SftpSink,
+    Error,
+};
+
+/// Destination the content of an image is extracted to.
+/// Paths are relative to the output root and use `/` as separator.
+pub trait OutputSink {
+    /// Prepares the output root, called once before anything else is written
+    fn prepare(&mut self) -> Result<(), Error>;
+
+    fn exists(&mut self, path: &str) -> Result<bool, Error>;
+
+    fn create_dir(&mut self, path: &str) -> Result<(), Error>;
+
+    /// Size of an already existing file, `None` if there is no such file
+    fn file_size(&mut self, path: &str) -> Result<Option<u64>, Error>;
+
+    /// Opens a writer for a file of the given size. The writer has to be dropped
+    /// before the file gets completed with [`OutputSink::finalize`].
+    fn create_file(&mut self, path: &str, size: u64) -> Result<Box<dyn Write + '_>, Error>;
+
+    /// Whether incomplete files can be continued with [`OutputSink::append_file`]
+    fn supports_resume(&self) -> bool {
+        false
+    }
+
+    /// Opens a writer appending to an incomplete file which currently has `offset` bytes
+    fn append_file(&mut self, path: &str, _offset: u64) -> Result<Box<dyn Write + '_>, Error> {
+        Err(Error::Invalid(format!(
+            "Resuming {:?} is not supported by this output",
+            self.display_path(path)
+        )))
+    }
+
+    /// Completes the file written last and verifies it has the expected size
+    fn finalize(&mut self, path: &str, size: u64) -> Result<(), Error>;
+
+    /// Passes the content of an existing file to `reader`, used to verify the output
+    fn read_file(
+        &mut self,
+        path: &str,
+        _reader: &mut dyn FnMut(&mut dyn Read) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        Err(Error::Invalid(format!(
+            "Reading back {:?} is not supported by this output",
+            self.display_path(path)
+        )))
+    }
+
+    /// Returns the names of the entries of an existing directory and whether they are
+    /// directories. An empty path lists the output root.
+    fn list_dir(&mut self, path: &str) -> Result<Vec<(String, bool)>, Error> {
+        Err(Error::Invalid(format!(
+            "Listing {:?} is not supported by this output",
+            self.display_path(path)
+        )))
+    }
+
+    /// Reestablishes the connection after an operation failed with `error` because
+    /// the connection broke. Returns whether the operation should be retried, which
+    /// is never the case for other errors like a refused command.
+    fn reconnect(&mut self, _error: &Error) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    /// Completes the output after all entries have been written
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Full location of a path, used for messages
+    fn display_path(&self, path: &str) -> String;
+}
+
+/// Opens the sink for an output location: an ftp://, ftps:// or sftp:// url,
+/// a .tar, .tar.zst or .zip archive, or otherwise a local directory.
+/// FTP and SFTP connections are established with the given settings. With
+/// `keep_existing`, the content of an existing local output directory is kept.
+pub fn open(
+    out_path: &str,
+    ftp_options: &FtpClientBuilder,
+    keep_existing: bool,
+) -> Result<Box<dyn OutputSink>, Error> {
+    let lower_path = out_path.to_lowercase();
+    if out_path.starts_with("sftp://") {
+        Ok(Box::new(SftpSink::connect(out_path, ftp_options)?))
+    } else if is_remote(out_path) {
+        Ok(Box::new(FtpSink::connect(out_path, ftp_options)?))
+    } else if lower_path.ends_with(".tar") {
+        Ok(Box::new(TarSink::new(out_path, false)))
+    } else if lower_path.ends_with(".tar.zst") {
+        Ok(Box::new(TarSink::new(out_path, true)))
+    } else if lower_path.ends_with(".zip") {
+        Ok(Box::new(ZipSink::new(out_path)))
+    } else if keep_existing {
+        Ok(Box::new(LocalSink::new(out_path).keep_existing()))
+    } else {
+        Ok(Box::new(LocalSink::new(out_path)))
+    }
+}
+
+/// Whether the output location is a server which accepts several connections at once
+pub(crate) fn is_remote(out_path: &str) -> bool {
+    ["ftp://", "ftps://", "sftp://"]
+        .iter()
+        .any(|scheme| out_path.starts_with(scheme))
+}
+
+/// Joins a relative sink path and an entry name
+pub(crate) fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+pub(crate) fn verify_size(location: &str, actual: u64, expected: u64) -> Result<(), Error> {
+    if actual != expected {
+        return Err(Error::Mismatch(format!(
+            "File verification failed. {:?} is corrupted.",
+            location
+        )));
+    }
+    Ok(())
+}
+
+/// Writes into a directory of the local file system, replacing it if it exists
+pub struct LocalSink {
+    root: PathBuf,
+    keep_existing: bool,
+    writer: Option<BufWriter<File>>,
+}
+
+impl LocalSink {
+    pub fn new(root: &str) -> Self {
+        LocalSink {
+            root: PathBuf::from(root),
+            keep_existing: false,
+            writer: None,
+        }
+    }
+
+    /// Writes into an existing directory without deleting its content first
+    pub fn keep_existing(mut self) -> Self {
+        self.keep_existing = true;
+        self
+    }
+}
+
+impl OutputSink for LocalSink {
+    fn prepare(&mut self) -> Result<(), Error> {
+        if self.root.exists() && !self.keep_existing {
+            print!("Output dir {:?} already exists. Replacing.", &self.root);
+            std::fs::remove_dir_all(&self.root).map_err(|e| {
+                Error::io(
+                    format!("Error deleting output directory {:?}", &self.root),
+                    e,
+                )
+            })?;
+        }
+
+        std::fs::create_dir_all(&self.root).map_err(|e| {
+            Error::io(
+                format!("Error creating output directory {:?}", &self.root),
+                e,
+            )
+        })
+    }
+
+    fn exists(&mut self, path: &str) -> Result<bool, Error> {
+        Ok(self.root.join(path).exists())
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), Error> {
+        let dir_path = self.root.join(path);
+        std::fs::create_dir(&dir_path).map_err(|e| {
+            Error::io(
+                format!("Error creating output directory {:?}", &dir_path),
+                e,
+            )
+        })
+    }
+
+    fn file_size(&mut self, path: &str) -> Result<Option<u64>, Error> {
+        Ok(std::fs::metadata(self.root.join(path))
+            .ok()
+            .map(|m| m.len()))
+    }
+
+    fn create_file(&mut self, path: &str, _size: u64) -> Result<Box<dyn Write + '_>, Error> {
+        let file_path = self.root.join(path);
+        let file = File::create(&file_path)
+            .map_err(|e| Error::io(format!("Error creating file {:?}", &file_path), e))?;
+        Ok(Box::new(self.writer.insert(BufWriter::new(file))))
+    }
+
+    fn finalize(&mut self, path: &str, size: u64) -> Result<(), Error> {
+        let file_path = self.root.join(path);
+        if let Some(mut writer) = self.writer.take() {
+            writer
+                .flush()
+                .map_err(|e| Error::io(format!("Error writing file {:?}", &file_path), e))?;
+        }
+        let metadata = std::fs::metadata(&file_path)
+            .map_err(|e| Error::io(format!("Error getting metadata for {:?}", &file_path), e))?;
+        verify_size(&self.display_path(path), metadata.len(), size)
+    }
+
+    fn read_file(
+        &mut self,
+        path: &str,
+        reader: &mut dyn FnMut(&mut dyn Read) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let file_path = self.root.join(path);
+        let file = File::open(&file_path)
+            .map_err(|e| Error::io(format!("Error opening file {:?}", &file_path), e))?;
+        reader(&mut std::io::BufReader::new(file))
+    }
+
+    fn list_dir(&mut self, path: &str) -> Result<Vec<(String, bool)>, Error> {
+        let dir_path = self.root.join(path);
+        let read_error =
+            |e: std::io::Error| Error::io(format!("Error reading directory {:?}", &dir_path), e);
+        let mut entries = Vec::new();
+        for dir_entry in std::fs::read_dir(&dir_path).map_err(read_error)? {
+            let dir_entry = dir_entry.map_err(read_error)?;
+            let is_dir = dir_entry.file_type().map_err(read_error)?.is_dir();
+            entries.push((dir_entry.file_name().to_string_lossy().to_string(), is_dir));
+        }
+        Ok(entries)
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        self.root.join(path).to_string_lossy().to_string()
+    }
+}
+
+/// Uploads to an FTP server, optionally over explicit TLS. The output root is the path of the url
+pub struct FtpSink {
+    client: FtpClient,
+    url: String,
+    options: FtpClientBuilder,
+    root: String,
+    writer: Option<Box<dyn Write>>,
+    reconnects_left: u32,
+}
+
+impl FtpSink {
+    pub fn connect(url: &str, options: &FtpClientBuilder) -> Result<Self, Error> {
+        let client = options.connect(url)?;
+        let root = client.get_path()?;
+        Ok(FtpSink {
+            client,
+            url: url.to_string(),
+            options: options.clone(),
+            root,
+            writer: None,
+            reconnects_left: options.get_reconnect_attempts(),
+        })
+    }
+
+    fn remote_path(&self, path: &str) -> String {
+        format!("{}/{}", self.root.trim_end_matches('/'), path)
+    }
+}
+
+impl OutputSink for FtpSink {
+    fn prepare(&mut self) -> Result<(), Error> {
+        self.client.create_dir_all(&self.root)
+    }
+
+    fn exists(&mut self, path: &str) -> Result<bool, Error> {
+        let remote_path = self.remote_path(path);
+        self.client.exists(&remote_path)
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), Error> {
+        let remote_path = self.remote_path(path);
+        self.client.mkdir(&remote_path)
+    }
+
+    fn file_size(&mut self, path: &str) -> Result<Option<u64>, Error> {
+        let remote_path = self.remote_path(path);
+        let file_size = self.client.get_file_size(&remote_path)?;
+        Ok((file_size >= 0).then_some(file_size as u64))
+    }
+
+    fn create_file(&mut self, path: &str, _size: u64) -> Result<Box<dyn Write + '_>, Error> {
+        let remote_path = self.remote_path(path);
+        let stream = self.client.put(&remote_path)?;
+        Ok(Box::new(self.writer.insert(Box::new(stream))))
+    }
+
+    fn supports_resume(&self) -> bool {
+        true
+    }
+
+    fn append_file(&mut self, path: &str, _offset: u64) -> Result<Box<dyn Write + '_>, Error> {
+        let remote_path = self.remote_path(path);
+        let stream = self.client.append(&remote_path)?;
+        Ok(Box::new(self.writer.insert(Box::new(stream))))
+    }
+
+    fn finalize(&mut self, path: &str, size: u64) -> Result<(), Error> {
+        let remote_path = self.remote_path(path);
+        if let Some(writer) = self.writer.take() {
+            self.client.put_close(writer)?;
+        }
+        let file_size = self.client.get_file_size(&remote_path)?;
+        verify_size(&remote_path, file_size as u64, size)?;
+        self.reconnects_left = self.options.get_reconnect_attempts();
+        Ok(())
+    }
+
+    fn read_file(
+        &mut self,
+        path: &str,
+        reader: &mut dyn FnMut(&mut dyn Read) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let remote_path = self.remote_path(path);
+        self.client.retr(&remote_path, reader)
+    }
+
+    fn list_dir(&mut self, path: &str) -> Result<Vec<(String, bool)>, Error> {
+        let remote_path = self.remote_path(path);
+        self.client.list_dir(&remote_path)
+    }
+
+    fn reconnect(&mut self, error: &Error) -> Result<bool, Error> {
+        if !error.is_connection_error() || self.reconnects_left == 0 {
+            return Ok(false);
+        }
+        self.reconnects_left -= 1;
+        println!();
+        println!("{}", error.report());
+        println!(
+            "Reconnecting to ftp server ({} attempts left).",
+            self.reconnects_left
+        );
+        self.writer = None;
+        self.client = self.options.connect(&self.url)?;
+        Ok(true)
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        self.remote_path(path)
+    }
+}
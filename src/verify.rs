@@ -0,0 +1,220 @@
+use std::{
+    cmp::min,
+    collections::HashMap,
+    io::{self, Read, Seek},
+};
+
+use pbr::{ProgressBar, Units};
+
+use crate::{
+    entry::DirEntry,
+    sink::{self, OutputSink},
+    Error, XIso, BUFFER_SIZE,
+};
+
+/// Differences between the image and previously extracted output, with paths
+/// relative to the output root
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Number of files with identical content
+    pub verified: u32,
+    /// Entries of the image which are not in the output
+    pub missing: Vec<String>,
+    /// Files and directories in the output which are not in the image
+    pub extra: Vec<String>,
+    /// Files whose size or content differ from the image
+    pub mismatched: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+impl<R: Read + Seek> XIso<R> {
+    /// Compares the output of a previous extraction byte by byte with the image.
+    /// The same entries as for extraction are expected, see [`XIso::set_filter`].
+    pub fn verify(&mut self, out_path: &str, skip_update: bool) -> Result<VerifyReport, Error> {
+        let mut sink = sink::open(out_path, &self.ftp_options, false)?;
+        self.verify_to(sink.as_mut(), skip_update)
+    }
+
+    /// Compares the content of a custom output sink with the image
+    pub fn verify_to(
+        &mut self,
+        sink: &mut dyn OutputSink,
+        skip_update: bool,
+    ) -> Result<VerifyReport, Error> {
+        let entries = self.selected_entries(skip_update);
+        let (_, _, data_size) = crate::count_entries(&entries);
+
+        let mut pb = ProgressBar::new(data_size);
+        pb.set_units(Units::Bytes);
+        pb.show_speed = false;
+        pb.show_time_left = false;
+
+        let mut report = VerifyReport::default();
+        self.verify_dir(sink, &entries, "", &mut report, &mut pb)?;
+        pb.finish_print("");
+        println!();
+
+        for path in report.missing.iter() {
+            println!("Missing:    {}", path);
+        }
+        for path in report.extra.iter() {
+            println!("Extra:      {}", path);
+        }
+        for path in report.mismatched.iter() {
+            println!("Mismatched: {}", path);
+        }
+        println!("Files verified: {}", report.verified);
+        Ok(report)
+    }
+
+    fn verify_dir(
+        &mut self,
+        sink: &mut dyn OutputSink,
+        entries: &[DirEntry],
+        parent: &str,
+        report: &mut VerifyReport,
+        pb: &mut ProgressBar<io::Stdout>,
+    ) -> Result<(), Error> {
+        let listing = match sink.list_dir(parent) {
+            Ok(listing) => listing,
+            // nothing was extracted to a missing output root, so every entry is
+            // missing. Outputs which can not be listed at all fail as Invalid.
+            Err(e) if parent.is_empty() && !matches!(e, Error::Invalid(_)) => {
+                if sink.exists(parent)? {
+                    return Err(e);
+                }
+                Vec::new()
+            }
+            Err(e) => return Err(e),
+        };
+        let mut existing: HashMap<String, (String, bool)> = listing
+            .into_iter()
+            .map(|(name, is_dir)| (name.to_lowercase(), (name, is_dir)))
+            .collect();
+
+        for entry in entries.iter() {
+            let path = sink::join_path(parent, &entry.name);
+            let Some((name, is_dir)) = existing.remove(&entry.name.to_lowercase()) else {
+                report.missing.push(path);
+                pb.add(data_size(entry));
+                continue;
+            };
+            let path = sink::join_path(parent, &name);
+
+            if is_dir != entry.is_dir() {
+                report.mismatched.push(path);
+                pb.add(data_size(entry));
+            } else if let Some(subdir) = &entry.subdir {
+                self.verify_dir(sink, subdir, &path, report, pb)?;
+            } else if entry.is_dir() {
+                // empty directory
+            } else if self.verify_file(sink, entry, &path, pb)? {
+                report.verified += 1;
+            } else {
+                report.mismatched.push(path);
+            }
+        }
+
+        let mut extra: Vec<String> = existing
+            .into_values()
+            .map(|(name, _)| sink::join_path(parent, &name))
+            .collect();
+        extra.sort();
+        report.extra.append(&mut extra);
+        Ok(())
+    }
+
+    /// Returns whether the output file has the same content as the entry
+    fn verify_file(
+        &mut self,
+        sink: &mut dyn OutputSink,
+        entry: &DirEntry,
+        path: &str,
+        pb: &mut ProgressBar<io::Stdout>,
+    ) -> Result<bool, Error> {
+        let entry_size = entry.size as u64;
+        if sink.file_size(path)? != Some(entry_size) {
+            pb.add(entry_size);
+            return Ok(false);
+        }
+        pb.message(format!("{}: ", &entry.name).as_str());
+
+        let mut image_reader = self.open(entry)?;
+        let mut image_buffer = vec![0; BUFFER_SIZE as usize];
+        let mut output_buffer = vec![0; BUFFER_SIZE as usize];
+        let mut equal = true;
+        sink.read_file(path, &mut |output| {
+            let mut remaining = entry_size;
+            while remaining > 0 {
+                let chunk_size = min(remaining, BUFFER_SIZE as u64) as usize;
+                image_reader
+                    .read_exact(&mut image_buffer[..chunk_size])
+                    .map_err(|e| Error::image("Error reading from ISO file", e))?;
+                match output.read_exact(&mut output_buffer[..chunk_size]) {
+                    Ok(()) => equal &= image_buffer[..chunk_size] == output_buffer[..chunk_size],
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        equal = false;
+                        break;
+                    }
+                    Err(e) => return Err(Error::io(format!("Error reading file {:?}", path), e)),
+                }
+                remaining -= chunk_size as u64;
+                pb.add(chunk_size as u64);
+            }
+            pb.add(remaining);
+
+            // the output is read to its end, remote servers expect complete transfers
+            let trailing = io::copy(output, &mut io::sink())
+                .map_err(|e| Error::io(format!("Error reading file {:?}", path), e))?;
+            equal &= trailing == 0;
+            Ok(())
+        })?;
+        Ok(equal)
+    }
+}
+
+/// Size of a file or of all files in a directory
+fn data_size(entry: &DirEntry) -> u64 {
+    match &entry.subdir {
+        Some(subdir) => crate::count_entries(subdir).2,
+        None if entry.is_dir() => 0,
+        None => entry.size as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use super::*;
+    use crate::writer::{self, Node};
+
+    #[test]
+    fn missing_output_root() {
+        let mut root = vec![
+            Node::file("default.xex".to_string(), 100, ()),
+            Node::dir("media".to_string(), Vec::new(), ()),
+        ];
+        let mut image = Vec::new();
+        writer::write_image(&mut image, &mut root, 0, |node, image| {
+            image
+                .write_all(&vec![0xab; node.size as usize])
+                .map_err(|e| Error::io("Error writing image", e))
+        })
+        .unwrap();
+
+        let out = std::env::temp_dir().join(format!("xiso-ex-{}-missing", std::process::id()));
+        let report = XIso::from_reader(Cursor::new(image))
+            .unwrap()
+            .verify(out.to_str().unwrap(), false)
+            .unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.missing, ["default.xex", "media"]);
+        assert_eq!(report.verified, 0);
+    }
+}
@@ -0,0 +1,481 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    entry::{ATTR_ARCHIVE, ATTR_DIRECTORY, ENTRY_HEADER_SIZE, MAX_NAME_LEN},
+    meta::{MAGIC, SECTOR_SIZE},
+    Error,
+};
+
+const HEADER_SECTOR: u32 = 32;
+const ROOT_DIR_SECTOR: u32 = HEADER_SECTOR + 1;
+
+/// Entry of a directory tree to be written into an image.
+/// `source` tells the data callback where the content of a file comes from.
+pub(crate) struct Node<S> {
+    pub name: String,
+    pub attributes: u8,
+    pub size: u32,
+    pub source: S,
+    pub children: Option<Vec<Node<S>>>,
+    sector: u32,
+}
+
+impl<S> Node<S> {
+    pub fn file(name: String, size: u32, source: S) -> Self {
+        Node {
+            name,
+            attributes: ATTR_ARCHIVE,
+            size,
+            source,
+            children: None,
+            sector: 0,
+        }
+    }
+
+    pub fn dir(name: String, children: Vec<Node<S>>, source: S) -> Self {
+        Node {
+            name,
+            attributes: ATTR_DIRECTORY,
+            size: 0,
+            source,
+            children: Some(children),
+            sector: 0,
+        }
+    }
+
+    fn entry_len(&self) -> usize {
+        (ENTRY_HEADER_SIZE as usize + self.name.len()).next_multiple_of(4)
+    }
+}
+
+/// Reads a local directory tree into nodes referencing the files by path.
+/// Symbolic links are skipped, names longer than the Xbox accepts are refused.
+pub(crate) fn read_dir_tree(path: &Path) -> Result<Vec<Node<PathBuf>>, Error> {
+    let dir = std::fs::read_dir(path)
+        .map_err(|e| Error::io(format!("Error reading directory {:?}", path), e))?;
+
+    let mut nodes = Vec::new();
+    for item in dir {
+        let item = item.map_err(|e| Error::io(format!("Error reading directory {:?}", path), e))?;
+        let item_path = item.path();
+        let name = item.file_name().to_string_lossy().to_string();
+        let metadata = std::fs::symlink_metadata(&item_path)
+            .map_err(|e| Error::io(format!("Error getting metadata for {:?}", &item_path), e))?;
+
+        if metadata.is_symlink() {
+            println!("Skipping symbolic link {:?}", &item_path);
+            continue;
+        }
+        if name.len() > MAX_NAME_LEN {
+            return Err(Error::Invalid(format!(
+                "Name of {:?} is {} bytes long, the Xbox accepts {}",
+                &item_path,
+                name.len(),
+                MAX_NAME_LEN
+            )));
+        }
+
+        if metadata.is_dir() {
+            let children = read_dir_tree(&item_path)?;
+            nodes.push(Node::dir(name, children, item_path));
+        } else {
+            let size = u32::try_from(metadata.len()).map_err(|_| {
+                Error::Invalid(format!(
+                    "File {:?} exceeds the 4GB XDVDFS limit",
+                    &item_path
+                ))
+            })?;
+            nodes.push(Node::file(name, size, item_path));
+        }
+    }
+    Ok(nodes)
+}
+
+/// Returns number of files and total file size
+pub(crate) fn count_nodes<S>(nodes: &[Node<S>]) -> (u32, u64) {
+    let mut totals = (0_u32, 0_u64);
+    for node in nodes.iter() {
+        if let Some(children) = node.children.as_ref() {
+            let (files, size) = count_nodes(children);
+            totals.0 += files;
+            totals.1 += size;
+        } else {
+            totals.0 += 1;
+            totals.1 += node.size as u64;
+        }
+    }
+    totals
+}
+
+/// Writes an XDVDFS image with the game partition at offset 0.
+/// Directory tables come first, followed by the file data in tree order.
+/// `copy_data` is called for every file and has to write exactly `size` bytes.
+pub(crate) fn write_image<W: Write, S>(
+    writer: &mut W,
+    root: &mut [Node<S>],
+    creation_time: u64,
+    mut copy_data: impl FnMut(&Node<S>, &mut W) -> Result<(), Error>,
+) -> Result<(), Error> {
+    if root.is_empty() {
+        return Err(Error::Invalid(
+            "Unable to create an image without any entries".to_string(),
+        ));
+    }
+    sort_entries(root);
+
+    let root_dir_size = table_size(root)?;
+    let mut next_sector = ROOT_DIR_SECTOR + root_dir_size / SECTOR_SIZE;
+    allocate_tables(root, &mut next_sector)?;
+    allocate_files(root, &mut next_sector);
+
+    let mut position = 0_u32;
+    pad_to_sector(writer, &mut position, HEADER_SECTOR)?;
+
+    let mut header = Vec::with_capacity(SECTOR_SIZE as usize);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&ROOT_DIR_SECTOR.to_le_bytes());
+    header.extend_from_slice(&root_dir_size.to_le_bytes());
+    header.extend_from_slice(&creation_time.to_le_bytes());
+    header.resize(SECTOR_SIZE as usize - MAGIC.len(), 0);
+    header.extend_from_slice(MAGIC);
+    write_sector_data(writer, &mut position, &header)?;
+
+    write_table(writer, &mut position, root)?;
+    write_tables(writer, &mut position, root)?;
+    write_files(writer, &mut position, root, &mut copy_data)?;
+
+    writer
+        .flush()
+        .map_err(|e| Error::io("Error flushing image writer", e))
+}
+
+/// Sorts every directory the way XDVDFS compares names: case-insensitive, byte-wise
+fn sort_entries<S>(entries: &mut [Node<S>]) {
+    entries.sort_by_key(|e| e.name.to_ascii_uppercase());
+    for entry in entries.iter_mut() {
+        if let Some(children) = entry.children.as_mut() {
+            sort_entries(children);
+        }
+    }
+}
+
+fn table_size<S>(entries: &[Node<S>]) -> Result<u32, Error> {
+    let mut table = Vec::new();
+    build_table(&mut table, entries)?;
+    Ok((table.len() as u32).next_multiple_of(SECTOR_SIZE))
+}
+
+/// Assigns sectors to the directory tables of all subdirectories, breadth first
+fn allocate_tables<S>(entries: &mut [Node<S>], next_sector: &mut u32) -> Result<(), Error> {
+    for entry in entries.iter_mut() {
+        if let Some(children) = entry.children.as_ref() {
+            if children.is_empty() {
+                // empty directories have no table at all
+                entry.size = 0;
+                entry.sector = 0;
+            } else {
+                entry.size = table_size(children)?;
+                entry.sector = *next_sector;
+                *next_sector += entry.size / SECTOR_SIZE;
+            }
+        }
+    }
+    for entry in entries.iter_mut() {
+        if let Some(children) = entry.children.as_mut() {
+            allocate_tables(children, next_sector)?;
+        }
+    }
+    Ok(())
+}
+
+fn allocate_files<S>(entries: &mut [Node<S>], next_sector: &mut u32) {
+    for entry in entries.iter_mut() {
+        if let Some(children) = entry.children.as_mut() {
+            allocate_files(children, next_sector);
+        } else if entry.size > 0 {
+            entry.sector = *next_sector;
+            *next_sector += entry.size.div_ceil(SECTOR_SIZE);
+        }
+    }
+}
+
+/// Serializes the entries of one directory as AVL tree. The entries are sorted, so
+/// taking the middle element as root of every subtree results in a balanced tree.
+/// Returns the offset of the subtree root in 4-byte units.
+fn build_table<S>(table: &mut Vec<u8>, entries: &[Node<S>]) -> Result<u16, Error> {
+    let mid = entries.len() / 2;
+    let entry = &entries[mid];
+    if entry.name.len() > u8::MAX as usize {
+        return Err(Error::Invalid(format!(
+            "File name too long: {:?}",
+            &entry.name
+        )));
+    }
+
+    // entries must not cross sector boundaries
+    let entry_len = entry.entry_len();
+    let sector_rest = SECTOR_SIZE as usize - table.len() % SECTOR_SIZE as usize;
+    if entry_len > sector_rest {
+        table.resize(table.len() + sector_rest, 0xFF);
+    }
+    let offset = table.len();
+    table.resize(offset + entry_len, 0xFF);
+
+    let left_offset = if mid > 0 {
+        build_table(table, &entries[..mid])?
+    } else {
+        0
+    };
+    let right_offset = if mid + 1 < entries.len() {
+        build_table(table, &entries[mid + 1..])?
+    } else {
+        0
+    };
+
+    let record = &mut table[offset..offset + ENTRY_HEADER_SIZE as usize + entry.name.len()];
+    record[0..2].copy_from_slice(&left_offset.to_le_bytes());
+    record[2..4].copy_from_slice(&right_offset.to_le_bytes());
+    record[4..8].copy_from_slice(&entry.sector.to_le_bytes());
+    record[8..12].copy_from_slice(&entry.size.to_le_bytes());
+    record[12] = entry.attributes;
+    record[13] = entry.name.len() as u8;
+    record[14..].copy_from_slice(entry.name.as_bytes());
+
+    u16::try_from(offset / 4).map_err(|_| Error::Invalid("Directory table too large".to_string()))
+}
+
+fn write_table<W: Write, S>(
+    writer: &mut W,
+    position: &mut u32,
+    entries: &[Node<S>],
+) -> Result<(), Error> {
+    let mut table = Vec::new();
+    build_table(&mut table, entries)?;
+    write_sector_data(writer, position, &table)
+}
+
+fn write_tables<W: Write, S>(
+    writer: &mut W,
+    position: &mut u32,
+    entries: &[Node<S>],
+) -> Result<(), Error> {
+    for entry in entries.iter() {
+        match entry.children.as_ref() {
+            Some(children) if !children.is_empty() => {
+                pad_to_sector(writer, position, entry.sector)?;
+                write_table(writer, position, children)?;
+            }
+            _ => {}
+        }
+    }
+    for entry in entries.iter() {
+        if let Some(children) = entry.children.as_ref() {
+            write_tables(writer, position, children)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_files<W: Write, S>(
+    writer: &mut W,
+    position: &mut u32,
+    entries: &[Node<S>],
+    copy_data: &mut impl FnMut(&Node<S>, &mut W) -> Result<(), Error>,
+) -> Result<(), Error> {
+    for entry in entries.iter() {
+        if let Some(children) = entry.children.as_ref() {
+            write_files(writer, position, children, copy_data)?;
+        } else if entry.size > 0 {
+            pad_to_sector(writer, position, entry.sector)?;
+            copy_data(entry, writer)?;
+            *position += entry.size.div_ceil(SECTOR_SIZE);
+            let rest = (entry.size % SECTOR_SIZE) as usize;
+            if rest > 0 {
+                write_padding(writer, SECTOR_SIZE as usize - rest, 0)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes `data` at the current sector and pads it with 0xFF to the sector boundary
+fn write_sector_data<W: Write>(
+    writer: &mut W,
+    position: &mut u32,
+    data: &[u8],
+) -> Result<(), Error> {
+    writer
+        .write_all(data)
+        .map_err(|e| Error::io("Error writing image", e))?;
+    let sectors = (data.len() as u32).div_ceil(SECTOR_SIZE);
+    let rest = (sectors * SECTOR_SIZE) as usize - data.len();
+    write_padding(writer, rest, 0xFF)?;
+    *position += sectors;
+    Ok(())
+}
+
+/// Fills the gap up to `sector` with zeros
+fn pad_to_sector<W: Write>(writer: &mut W, position: &mut u32, sector: u32) -> Result<(), Error> {
+    if *position < sector {
+        write_padding(writer, ((sector - *position) * SECTOR_SIZE) as usize, 0)?;
+        *position = sector;
+    }
+    Ok(())
+}
+
+fn write_padding<W: Write>(writer: &mut W, count: usize, fill: u8) -> Result<(), Error> {
+    let buffer = [fill; SECTOR_SIZE as usize];
+    let mut remaining = count;
+    while remaining > 0 {
+        let chunk = remaining.min(buffer.len());
+        writer
+            .write_all(&buffer[..chunk])
+            .map_err(|e| Error::io("Error writing image", e))?;
+        remaining -= chunk;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use super::*;
+    use crate::XIso;
+
+    fn file(name: &str, content: &[u8]) -> Node<Vec<u8>> {
+        Node::file(name.to_string(), content.len() as u32, content.to_vec())
+    }
+
+    fn dir(name: &str, children: Vec<Node<Vec<u8>>>) -> Node<Vec<u8>> {
+        Node::dir(name.to_string(), children, Vec::new())
+    }
+
+    fn write_to_vec(root: &mut [Node<Vec<u8>>]) -> Vec<u8> {
+        let mut image = Vec::new();
+        write_image(&mut image, root, 0, |node, image| {
+            image
+                .write_all(&node.source)
+                .map_err(|e| Error::io("Error writing image", e))
+        })
+        .unwrap();
+        image
+    }
+
+    #[test]
+    fn written_image_reads_back() {
+        let mut root = vec![
+            file("default.xex", b"XEX data"),
+            dir(
+                "media",
+                vec![
+                    file("Intro.xmv", &[0xab; 5000]),
+                    dir("empty", Vec::new()),
+                    file("a.txt", b"a"),
+                ],
+            ),
+            file("empty.bin", b""),
+        ];
+        let image = write_to_vec(&mut root);
+        assert_eq!(image.len() % SECTOR_SIZE as usize, 0);
+
+        let mut xiso = XIso::from_reader(Cursor::new(image)).unwrap();
+        let listed: Vec<(String, u32, bool)> = xiso
+            .list_entries()
+            .into_iter()
+            .map(|e| (e.path, if e.is_dir { 0 } else { e.size }, e.is_dir))
+            .collect();
+        let expected = [
+            ("/default.xex", 8, false),
+            ("/empty.bin", 0, false),
+            ("/media", 0, true),
+            ("/media/a.txt", 1, false),
+            ("/media/empty", 0, true),
+            ("/media/Intro.xmv", 5000, false),
+        ];
+        let expected: Vec<(String, u32, bool)> = expected
+            .iter()
+            .map(|&(path, size, is_dir)| (path.to_string(), size, is_dir))
+            .collect();
+        assert_eq!(listed, expected);
+
+        for (path, content) in [
+            ("default.xex", b"XEX data".to_vec()),
+            ("media/intro.xmv", vec![0xab; 5000]),
+            ("media/a.txt", b"a".to_vec()),
+        ] {
+            let mut data = Vec::new();
+            xiso.open_path(path)
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+            assert_eq!(data, content, "content of {}", path);
+        }
+    }
+
+    #[test]
+    fn large_directory_spans_table_sectors() {
+        let mut root: Vec<_> = (0..300)
+            .map(|i| file(&format!("file_with_a_long_name_{:03}.bin", i), &[i as u8]))
+            .collect();
+        let image = write_to_vec(&mut root);
+
+        let xiso = XIso::from_reader(Cursor::new(image)).unwrap();
+        assert!(xiso.meta().root_dir_size > SECTOR_SIZE);
+        let names: Vec<String> = xiso.list_entries().into_iter().map(|e| e.path).collect();
+        let expected: Vec<String> = (0..300)
+            .map(|i| format!("/file_with_a_long_name_{:03}.bin", i))
+            .collect();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn empty_tree_is_rejected() {
+        let mut image = Vec::new();
+        let result = write_image(
+            &mut image,
+            &mut [] as &mut [Node<Vec<u8>>],
+            0,
+            |_, _| Ok(()),
+        );
+        assert!(matches!(result, Err(Error::Invalid(_))));
+    }
+
+    fn source_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("xiso-ex-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(dir.join("media")).unwrap();
+        std::fs::write(dir.join("default.xex"), b"XEX data").unwrap();
+        dir
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_skipped() {
+        let dir = source_dir("symlinks");
+        // a link back to the parent would recurse forever if it was followed
+        std::os::unix::fs::symlink("..", dir.join("media/loop")).unwrap();
+        std::os::unix::fs::symlink("default.xex", dir.join("link.xex")).unwrap();
+
+        let mut nodes = read_dir_tree(&dir).unwrap();
+        nodes.sort_by(|a, b| a.name.cmp(&b.name));
+        let names: Vec<&str> = nodes.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, ["default.xex", "media"]);
+        assert_eq!(nodes[1].children.as_ref().map(Vec::len), Some(0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn long_names_are_rejected() {
+        let dir = source_dir("long-names");
+        let name = "n".repeat(MAX_NAME_LEN);
+        std::fs::write(dir.join("media").join(&name), b"").unwrap();
+        assert_eq!(read_dir_tree(&dir).unwrap().len(), 2);
+
+        std::fs::write(dir.join("media").join(name + "n"), b"").unwrap();
+        assert!(matches!(read_dir_tree(&dir), Err(Error::Invalid(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}